@@ -14,7 +14,9 @@ openssl req -x509 -nodes -days 365 \
   -config ca.conf \
   -passin pass:
 
+mkdir -p docker-compose/aas
 cp config.toml.in docker-compose/aas/config.toml
+cp localhost.key localhost.crt ca.key ca.crt docker-compose/aas/
 cp cdh-config.toml docker-compose/guest-components/cdh-config.toml
 
 replace_section() {
@@ -26,9 +28,4 @@ replace_section() {
     sed -i "s/${placeholder}/${content}/g" "$file_to_modify"
 }
 
-replace_section localhost.key @HTTPS_PRIVATE_KEY@ docker-compose/aas/config.toml
-replace_section localhost.crt @HTTPS_CERT@ docker-compose/aas/config.toml
-replace_section ca.crt @CLIENT_ROOT_CA_CERT@ docker-compose/aas/config.toml
-replace_section ca.key @CLIENT_CA_PRIVATE_KEY@ docker-compose/aas/config.toml
-replace_section ca.crt @CLIENT_CA_CERT@ docker-compose/aas/config.toml
 replace_section localhost.crt @KBS_HTTPS_CERT@ docker-compose/guest-components/cdh-config.toml
\ No newline at end of file
@@ -0,0 +1,400 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Objects and encodings of the ACME (RFC 8555) subset served by
+//! [`crate::server::Acme`].
+//!
+//! The only identifier type is `permanent-identifier`, whose value is a
+//! registered id, and the only challenge type is `device-attest-01`. Each
+//! order has exactly one identifier, so the order, its authorization and its
+//! challenge share one object and one id. To answer the challenge, the
+//! client posts `{"attObj": base64url(json)}` where `json` is
+//! `{"tee": <tee>, "evidence": <TEE evidence>}`. The evidence binds the
+//! challenge token as nonce with the key authorization
+//! `<token>.<account key thumbprint>` as runtime data.
+//!
+//! Only ES256 account keys are supported.
+
+use std::fmt;
+
+use anyhow::*;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use kbs_types::Tee;
+use p256::{
+    ecdsa::{signature::Verifier, Signature, VerifyingKey},
+    PublicKey,
+};
+use rand::{thread_rng, Rng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::server::VerifiedEvidence;
+
+pub const IDENTIFIER_TYPE: &str = "permanent-identifier";
+pub const CHALLENGE_TYPE: &str = "device-attest-01";
+
+/// An ACME error, reported to the client as a problem document (RFC 7807).
+#[derive(Debug)]
+pub struct Problem {
+    /// The ACME error type without the `urn:ietf:params:acme:error:` prefix.
+    pub kind: &'static str,
+    pub detail: String,
+    /// HTTP status of the problem.
+    pub status: u16,
+}
+
+impl Problem {
+    pub fn new(kind: &'static str, detail: impl Into<String>) -> Self {
+        let status = match kind {
+            "unauthorized" | "orderNotReady" => 403,
+            "rateLimited" => 429,
+            "serverInternal" => 500,
+            _ => 400,
+        };
+        Self {
+            kind,
+            detail: detail.into(),
+            status,
+        }
+    }
+
+    /// The server can not answer for now, e.g. as the attestation service is
+    /// unavailable. The client may retry the same request.
+    pub fn unavailable(detail: impl Into<String>) -> Self {
+        Self {
+            status: 503,
+            ..Self::new("serverInternal", detail)
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": format!("urn:ietf:params:acme:error:{}", self.kind),
+            "detail": self.detail,
+            "status": self.status,
+        })
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.detail)
+    }
+}
+
+impl std::error::Error for Problem {}
+
+/// Shorthand to return a [`Problem`] as an [`anyhow::Error`].
+pub(crate) fn problem(kind: &'static str, detail: impl Into<String>) -> Error {
+    Problem::new(kind, detail).into()
+}
+
+/// The body of an ACME response.
+pub enum AcmeBody {
+    Json(Value),
+    /// `application/pem-certificate-chain`
+    PemChain(String),
+}
+
+pub struct AcmeReply {
+    /// Whether a new object was created, i.e. `201 Created`.
+    pub created: bool,
+    pub location: Option<String>,
+    pub body: AcmeBody,
+}
+
+impl AcmeReply {
+    pub(crate) fn json(body: Value) -> Self {
+        Self {
+            created: false,
+            location: None,
+            body: AcmeBody::Json(body),
+        }
+    }
+}
+
+/// URLs of the ACME resources, all under `{base}/acme`.
+pub(crate) struct Urls<'a> {
+    pub base: &'a str,
+}
+
+impl Urls<'_> {
+    pub fn new_nonce(&self) -> String {
+        format!("{}/acme/new-nonce", self.base)
+    }
+
+    pub fn new_account(&self) -> String {
+        format!("{}/acme/new-account", self.base)
+    }
+
+    pub fn new_order(&self) -> String {
+        format!("{}/acme/new-order", self.base)
+    }
+
+    pub fn account(&self, id: &str) -> String {
+        format!("{}/acme/account/{id}", self.base)
+    }
+
+    pub fn order(&self, id: &str) -> String {
+        format!("{}/acme/order/{id}", self.base)
+    }
+
+    pub fn authorization(&self, id: &str) -> String {
+        format!("{}/acme/authz/{id}", self.base)
+    }
+
+    pub fn challenge(&self, id: &str) -> String {
+        format!("{}/acme/chall/{id}", self.base)
+    }
+
+    pub fn finalize(&self, id: &str) -> String {
+        format!("{}/acme/order/{id}/finalize", self.base)
+    }
+
+    pub fn certificate(&self, id: &str) -> String {
+        format!("{}/acme/cert/{id}", self.base)
+    }
+}
+
+/// A random base64url string, used for nonces and object ids.
+pub(crate) fn random_id() -> String {
+    let mut id = [0u8; 16];
+    thread_rng().fill(&mut id[..]);
+    URL_SAFE_NO_PAD.encode(id)
+}
+
+/// A JWS in flattened JSON serialization, as posted by ACME clients.
+#[derive(Deserialize)]
+pub struct Jws {
+    protected: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Protected {
+    pub alg: String,
+    pub nonce: String,
+    pub url: String,
+    pub jwk: Option<Value>,
+    pub kid: Option<String>,
+}
+
+impl Jws {
+    pub(crate) fn protected(&self) -> Result<Protected> {
+        let protected = URL_SAFE_NO_PAD
+            .decode(&self.protected)
+            .map_err(|_| problem("malformed", "protected header is not base64url"))?;
+        serde_json::from_slice(&protected)
+            .map_err(|e| problem("malformed", format!("illegal protected header: {e}")))
+    }
+
+    pub(crate) fn verify(&self, key: &VerifyingKey) -> Result<()> {
+        let signature = URL_SAFE_NO_PAD
+            .decode(&self.signature)
+            .ok()
+            .and_then(|signature| Signature::from_slice(&signature).ok())
+            .ok_or_else(|| problem("malformed", "illegal JWS signature"))?;
+        let signing_input = format!("{}.{}", self.protected, self.payload);
+        key.verify(signing_input.as_bytes(), &signature)
+            .map_err(|_| problem("unauthorized", "JWS signature does not verify"))
+    }
+
+    /// The payload, or `None` for a POST-as-GET request.
+    pub(crate) fn payload<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        if self.payload.is_empty() {
+            return Ok(None);
+        }
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(&self.payload)
+            .map_err(|_| problem("malformed", "payload is not base64url"))?;
+        let payload = serde_json::from_slice(&payload)
+            .map_err(|e| problem("malformed", format!("illegal payload: {e}")))?;
+        Ok(Some(payload))
+    }
+}
+
+/// The account key of `jwk` and its RFC 7638 thumbprint, which is also the
+/// account id.
+pub(crate) fn account_key(jwk: &Value) -> Result<(VerifyingKey, String)> {
+    let key = PublicKey::from_jwk_str(&jwk.to_string())
+        .map_err(|_| problem("badPublicKey", "account key must be a P-256 JWK"))?;
+
+    // `to_jwk` only has the required members, and a `Value` sorts them.
+    let canonical = serde_json::to_value(key.to_jwk())?.to_string();
+    let thumbprint = URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()));
+    Ok((VerifyingKey::from(key), thumbprint))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Account {
+    pub key: Value,
+    #[serde(default)]
+    pub contact: Vec<String>,
+}
+
+impl Account {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": "valid",
+            "contact": self.contact,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct NewAccount {
+    #[serde(default)]
+    pub contact: Vec<String>,
+    #[serde(default, rename = "onlyReturnExisting")]
+    pub only_return_existing: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct NewOrder {
+    pub identifiers: Vec<Identifier>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ChallengeResponse {
+    #[serde(rename = "attObj")]
+    pub att_obj: Option<String>,
+}
+
+/// The content of `attObj`.
+#[derive(Deserialize)]
+pub(crate) struct AttestationObject {
+    pub tee: Tee,
+    pub evidence: String,
+}
+
+impl ChallengeResponse {
+    pub fn attestation_object(&self) -> Result<Option<AttestationObject>> {
+        let Some(att_obj) = &self.att_obj else {
+            return Ok(None);
+        };
+
+        let att_obj = URL_SAFE_NO_PAD
+            .decode(att_obj)
+            .map_err(|_| problem("malformed", "attObj is not base64url"))?;
+        let att_obj = serde_json::from_slice(&att_obj).map_err(|e| {
+            problem(
+                "badAttestationStatement",
+                format!("illegal attestation object: {e}"),
+            )
+        })?;
+        Ok(Some(att_obj))
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Finalize {
+    /// base64url of the DER CSR.
+    pub csr: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+}
+
+/// An order together with its only authorization and challenge.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Order {
+    pub account: String,
+    /// The registered id the certificate is ordered for.
+    pub id: String,
+    pub status: Status,
+    pub expires: DateTime<Utc>,
+    /// Nonce of the `device-attest-01` challenge.
+    pub token: String,
+    pub verified: Option<VerifiedEvidence>,
+    pub validated: Option<DateTime<Utc>>,
+    /// Problem document of a failed challenge.
+    pub error: Option<Value>,
+    /// PEM chain of the issued certificate.
+    pub certificate: Option<String>,
+}
+
+impl Order {
+    /// Expire the order if its challenge has not been answered in time.
+    pub fn check_expiry(&mut self) {
+        if self.expires < Utc::now() && matches!(self.status, Status::Pending | Status::Ready) {
+            self.status = Status::Invalid;
+        }
+    }
+
+    fn identifier(&self) -> Value {
+        json!({ "type": IDENTIFIER_TYPE, "value": self.id })
+    }
+
+    fn authorization_status(&self) -> Status {
+        match self.status {
+            Status::Pending => Status::Pending,
+            Status::Invalid if self.verified.is_none() => Status::Invalid,
+            _ => Status::Valid,
+        }
+    }
+
+    pub fn to_json(&self, urls: &Urls, id: &str) -> Value {
+        let mut order = json!({
+            "status": self.status,
+            "expires": self.expires.to_rfc3339(),
+            "identifiers": [self.identifier()],
+            "authorizations": [urls.authorization(id)],
+            "finalize": urls.finalize(id),
+        });
+        if self.certificate.is_some() {
+            order["certificate"] = urls.certificate(id).into();
+        }
+
+        order
+    }
+
+    pub fn authorization_json(&self, urls: &Urls, id: &str) -> Value {
+        json!({
+            "status": self.authorization_status(),
+            "expires": self.expires.to_rfc3339(),
+            "identifier": self.identifier(),
+            "challenges": [self.challenge_json(urls, id)],
+        })
+    }
+
+    pub fn challenge_json(&self, urls: &Urls, id: &str) -> Value {
+        let status = match self.authorization_status() {
+            Status::Valid => Status::Valid,
+            Status::Invalid => Status::Invalid,
+            _ => Status::Pending,
+        };
+        let mut challenge = json!({
+            "type": CHALLENGE_TYPE,
+            "url": urls.challenge(id),
+            "token": self.token,
+            "status": status,
+        });
+        if let Some(validated) = &self.validated {
+            challenge["validated"] = validated.to_rfc3339().into();
+        }
+        if let Some(error) = &self.error {
+            challenge["error"] = error.clone();
+        }
+
+        challenge
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+#[derive(Debug)]
+enum State {
+    /// Consecutive failures so far.
+    Closed(u32),
+    /// Fail fast until then.
+    Open(Instant),
+    /// One request was let through at that time to probe the backend.
+    HalfOpen(Instant),
+}
+
+/// Fails fast once a backend failed `threshold` times in a row. After
+/// `open_for`, one request is let through: the circuit closes if it
+/// succeeds, and opens again if it fails. If it neither succeeds nor fails,
+/// e.g. it was cancelled, another one is let through after `open_for`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, open_for: Duration) -> Self {
+        Self {
+            threshold,
+            open_for,
+            state: Mutex::new(State::Closed(0)),
+        }
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow!("circuit breaker poisoned"))
+    }
+
+    /// Whether a request may be sent. Callers must report its outcome.
+    pub fn allow(&self) -> Result<bool> {
+        let mut state = self.state()?;
+        let now = Instant::now();
+        match *state {
+            State::Closed(_) => Ok(true),
+            State::Open(until) if now < until => Ok(false),
+            State::HalfOpen(since) if now < since + self.open_for => Ok(false),
+            State::Open(_) | State::HalfOpen(_) => {
+                *state = State::HalfOpen(now);
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn succeeded(&self) -> Result<()> {
+        let mut state = self.state()?;
+        if matches!(*state, State::HalfOpen(_)) {
+            info!("circuit closed");
+        }
+        *state = State::Closed(0);
+        Ok(())
+    }
+
+    pub fn failed(&self) -> Result<()> {
+        let mut state = self.state()?;
+        let failures = match *state {
+            State::Closed(failures) => failures + 1,
+            // The probe failed.
+            State::Open(_) | State::HalfOpen(_) => self.threshold,
+        };
+        if failures < self.threshold {
+            *state = State::Closed(failures);
+            return Ok(());
+        }
+
+        if matches!(*state, State::Closed(_)) {
+            warn!("circuit opened after {failures} failures");
+        }
+        *state = State::Open(Instant::now() + self.open_for);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPEN_FOR: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn opens_after_threshold() {
+        let circuit = CircuitBreaker::new(2, OPEN_FOR);
+        circuit.failed().unwrap();
+        assert!(circuit.allow().unwrap());
+        circuit.failed().unwrap();
+        assert!(!circuit.allow().unwrap());
+    }
+
+    #[test]
+    fn counts_failures_in_a_row() {
+        let circuit = CircuitBreaker::new(2, OPEN_FOR);
+        circuit.failed().unwrap();
+        circuit.succeeded().unwrap();
+        circuit.failed().unwrap();
+        assert!(circuit.allow().unwrap());
+    }
+
+    #[test]
+    fn half_open_lets_one_probe_through() {
+        let circuit = CircuitBreaker::new(1, OPEN_FOR);
+        circuit.failed().unwrap();
+        std::thread::sleep(OPEN_FOR);
+
+        assert!(circuit.allow().unwrap());
+        assert!(!circuit.allow().unwrap(), "the probe is in flight");
+        circuit.succeeded().unwrap();
+        assert!(circuit.allow().unwrap());
+        assert!(circuit.allow().unwrap());
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let circuit = CircuitBreaker::new(3, OPEN_FOR);
+        for _ in 0..3 {
+            circuit.failed().unwrap();
+        }
+        std::thread::sleep(OPEN_FOR);
+
+        assert!(circuit.allow().unwrap());
+        circuit.failed().unwrap();
+        assert!(!circuit.allow().unwrap());
+        std::thread::sleep(OPEN_FOR);
+        assert!(circuit.allow().unwrap());
+    }
+
+    #[test]
+    fn lost_probe_is_replaced() {
+        let circuit = CircuitBreaker::new(1, OPEN_FOR);
+        circuit.failed().unwrap();
+        std::thread::sleep(OPEN_FOR);
+
+        // The probe never reports back.
+        assert!(circuit.allow().unwrap());
+        assert!(!circuit.allow().unwrap());
+        std::thread::sleep(OPEN_FOR);
+        assert!(circuit.allow().unwrap());
+    }
+}
@@ -2,15 +2,85 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
 use kbs_types::Tee;
+use log::warn;
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue},
+    Certificate, Identity, StatusCode,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tracing::instrument;
+
+use super::{circuit::CircuitBreaker, Unavailable};
+use crate::telemetry;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Timeouts, retries and circuit breaking of the requests to the AS.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ClientOptions {
+    /// Seconds to connect to the AS.
+    pub connect_timeout: u64,
+    /// Seconds for one attempt of a request.
+    pub request_timeout: u64,
+    /// Retries of a request that got no answer, or 502, 503 or 504.
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled for each next one.
+    pub retry_backoff_ms: u64,
+    /// Failed requests in a row after which requests fail fast.
+    pub circuit_failure_threshold: u32,
+    /// Seconds to fail fast before trying the AS again.
+    pub circuit_open_secs: u64,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 5,
+            request_timeout: 30,
+            retries: 2,
+            retry_backoff_ms: 200,
+            circuit_failure_threshold: 5,
+            circuit_open_secs: 30,
+        }
+    }
+}
+
+/// How the AS is trusted and how `aas` authenticates to it. All values are
+/// PEM or the token itself.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Credentials {
+    /// Trust only this CA for an `https` address, instead of the system
+    /// roots.
+    pub ca_cert: Option<String>,
+    /// Client certificate chain for mutual TLS.
+    pub client_cert: Option<String>,
+    /// PKCS#8 private key of `client_cert`.
+    pub client_key: Option<String>,
+    /// Sent as `Authorization: Bearer <token>`.
+    pub bearer_token: Option<String>,
+}
 
 #[derive(Debug)]
 pub struct Client {
     addr: String,
     client: reqwest::Client,
+    options: ClientOptions,
+    circuit: CircuitBreaker,
+}
+
+/// Why an attempt failed.
+enum Failure {
+    /// Worth retrying, e.g. the AS is restarting.
+    Transient(anyhow::Error),
+    /// The AS answered, e.g. the evidence was rejected.
+    Answered(anyhow::Error),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -47,11 +117,71 @@ fn to_tee_string(tee: Tee) -> String {
 }
 
 impl Client {
-    pub fn new(addr: String) -> Self {
-        let client = reqwest::Client::new();
-        Self { client, addr }
+    pub fn new(addr: String) -> Result<Self> {
+        Self::with_options(addr, ClientOptions::default(), &Credentials::default())
     }
 
+    pub fn with_options(
+        addr: String,
+        options: ClientOptions,
+        credentials: &Credentials,
+    ) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(options.connect_timeout))
+            .timeout(Duration::from_secs(options.request_timeout));
+
+        if let Some(ca_cert) = &credentials.ca_cert {
+            let ca_cert = Certificate::from_pem(ca_cert.as_bytes()).context("parse AS CA cert")?;
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(ca_cert);
+        }
+        match (&credentials.client_cert, &credentials.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8_pem(cert.as_bytes(), key.as_bytes())
+                    .context("parse AS client cert and key")?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("AS client_cert and client_key must be given together"),
+        }
+        if let Some(token) = &credentials.bearer_token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+                .context("invalid AS bearer token")?;
+            value.set_sensitive(true);
+            builder =
+                builder.default_headers(HeaderMap::from_iter([(header::AUTHORIZATION, value)]));
+        }
+        let authenticated = credentials.client_cert.is_some() || credentials.bearer_token.is_some();
+        if authenticated && !addr.starts_with("https://") {
+            warn!("credentials for the AS are sent over plain {addr}");
+        }
+
+        let client = builder.build().context("build AS client")?;
+        let circuit = CircuitBreaker::new(
+            options.circuit_failure_threshold,
+            Duration::from_secs(options.circuit_open_secs),
+        );
+
+        Ok(Self {
+            addr,
+            client,
+            options,
+            circuit,
+        })
+    }
+
+    /// Any HTTP response from the AS counts as reachable.
+    pub async fn probe(&self) -> Result<()> {
+        self.client
+            .get(&self.addr)
+            .timeout(PROBE_TIMEOUT)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "coco_restful.attest", skip_all, fields(addr = %self.addr))]
     pub async fn attest(
         &self,
         evidence: &str,
@@ -59,7 +189,7 @@ impl Client {
         nonce: &str,
         csr: &str,
         tee: Tee,
-    ) -> Result<()> {
+    ) -> Result<String> {
         let req = AttestationRequest {
             tee: to_tee_string(tee),
             evidence: evidence.into(),
@@ -74,12 +204,172 @@ impl Client {
         };
 
         let req = serde_json::to_string(&req)?;
-        self.client
-            .post(format!("{}/attestation", self.addr))
-            .body(req)
+        if !self.circuit.allow()? {
+            return Err(anyhow!(Unavailable(format!(
+                "{} failed repeatedly, circuit open",
+                self.addr
+            ))));
+        }
+
+        let mut backoff = Duration::from_millis(self.options.retry_backoff_ms);
+        let mut attempt = 0;
+        loop {
+            match self.try_attest(&req).await {
+                Ok(token) => {
+                    self.circuit.succeeded()?;
+                    return Ok(token);
+                }
+                Err(Failure::Answered(e)) => {
+                    self.circuit.succeeded()?;
+                    return Err(e);
+                }
+                Err(Failure::Transient(e)) if attempt < self.options.retries => {
+                    warn!("AS request failed, retry in {backoff:?}: {e:#}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(Failure::Transient(e)) => {
+                    self.circuit.failed()?;
+                    return Err(anyhow!(Unavailable(format!("{e:#}"))));
+                }
+            }
+        }
+    }
+
+    async fn try_attest(&self, req: &str) -> std::result::Result<String, Failure> {
+        let mut builder = self.client.post(format!("{}/attestation", self.addr));
+        for (name, value) in telemetry::current_context() {
+            builder = builder.header(name, value);
+        }
+        let response = builder
+            .body(req.to_string())
             .send()
-            .await?;
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
 
-        Ok(())
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
+        match status {
+            s if s.is_success() => Ok(body),
+            StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => {
+                Err(Failure::Transient(anyhow!("AS answered {status}: {body}")))
+            }
+            _ => Err(Failure::Answered(anyhow!("AS answered {status}: {body}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+
+    /// An AS answering the requests with `statuses` in turn, then with the
+    /// last one. Returns its address and the number of requests so far.
+    fn stub(statuses: &[u16]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let statuses = statuses.to_vec();
+        let count = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                reader.read_exact(&mut vec![0; length]).unwrap();
+
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                let status = statuses[n.min(statuses.len() - 1)];
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\ncontent-length: 5\r\nconnection: close\r\n\r\ntoken"
+                )
+                .unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    fn client(addr: String, retries: u32, circuit_failure_threshold: u32) -> Client {
+        let options = ClientOptions {
+            retries,
+            retry_backoff_ms: 1,
+            circuit_failure_threshold,
+            circuit_open_secs: 60,
+            ..Default::default()
+        };
+        Client::with_options(addr, options, &Credentials::default()).unwrap()
+    }
+
+    async fn attest(client: &Client) -> Result<String> {
+        client
+            .attest("e", vec!["default"], "n", "c", Tee::Sample)
+            .await
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let (addr, requests) = stub(&[503, 502, 200]);
+        let client = client(addr, 2, 5);
+        assert_eq!(attest(&client).await.unwrap(), "token");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let (addr, requests) = stub(&[503]);
+        let client = client(addr, 1, 5);
+        let error = attest(&client).await.unwrap_err();
+        assert!(error.downcast_ref::<Unavailable>().is_some(), "{error:#}");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_answers() {
+        let (addr, requests) = stub(&[401]);
+        let client = client(addr, 2, 1);
+        let error = attest(&client).await.unwrap_err();
+        assert!(error.downcast_ref::<Unavailable>().is_none(), "{error:#}");
+        // Nor do answers open the circuit.
+        assert!(attest(&client).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn open_circuit_fails_fast() {
+        let (addr, requests) = stub(&[503, 200]);
+        let client = client(addr, 0, 1);
+        assert!(attest(&client).await.is_err());
+
+        let error = attest(&client).await.unwrap_err();
+        assert!(error.to_string().contains("circuit open"), "{error:#}");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
     }
 }
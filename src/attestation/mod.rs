@@ -2,17 +2,46 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod circuit;
 pub mod coco_restful;
+pub mod routing;
+
+use std::fmt;
 
 use anyhow::Result;
 use kbs_types::Tee;
 
+/// The AS could not be reached, as opposed to rejecting the evidence.
+#[derive(Debug)]
+pub struct Unavailable(pub String);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "attestation service unavailable: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
 #[derive(Debug)]
 pub enum AttestationService {
     CoCoRestful(coco_restful::Client),
+    #[cfg(test)]
+    Mock(Mock),
+}
+
+/// How a mock attestation service answers.
+#[cfg(test)]
+#[derive(Debug)]
+pub enum Mock {
+    /// Accepts any evidence, returning its name as token.
+    Accept(&'static str),
+    Reject,
+    Unavailable,
 }
 
 impl AttestationService {
+    /// Verify `evidence` and return the attestation token of the AS.
     pub async fn verify(
         &self,
         evidence: &str,
@@ -20,11 +49,28 @@ impl AttestationService {
         nonce: &str,
         csr: &str,
         tee: Tee,
-    ) -> Result<()> {
+    ) -> Result<String> {
         match self {
             AttestationService::CoCoRestful(client) => {
                 client.attest(evidence, policy_ids, nonce, csr, tee).await
             }
+            #[cfg(test)]
+            AttestationService::Mock(mock) => match mock {
+                Mock::Accept(token) => Ok(token.to_string()),
+                Mock::Reject => anyhow::bail!("evidence rejected"),
+                Mock::Unavailable => Err(Unavailable("mock".to_string()).into()),
+            },
+        }
+    }
+
+    /// Check that the AS is reachable, without verifying anything.
+    pub async fn probe(&self) -> Result<()> {
+        match self {
+            AttestationService::CoCoRestful(client) => client.probe().await,
+            #[cfg(test)]
+            AttestationService::Mock(Mock::Unavailable) => anyhow::bail!("unreachable"),
+            #[cfg(test)]
+            AttestationService::Mock(_) => Ok(()),
         }
     }
 }
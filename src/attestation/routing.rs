@@ -0,0 +1,381 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Named attestation services, and which of them verify the evidence of a
+//! TEE type, optionally per identity.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail, Result};
+use futures_util::future::join_all;
+use kbs_types::Tee;
+use log::warn;
+use serde::Deserialize;
+
+use super::{AttestationService, Unavailable};
+
+/// Name of the backend given to [`Backends::new`] first. It verifies the
+/// evidence no route matches.
+pub const DEFAULT_BACKEND: &str = "default";
+
+/// Evidence of `tee`, from an identity starting with `id_prefix` if given,
+/// is verified by `backends`. Without `quorum`, the first backend that is
+/// available answers. With it, all backends are asked and at least `quorum`
+/// of them must accept the evidence.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    pub tee: Tee,
+    pub id_prefix: Option<String>,
+    pub backends: Vec<String>,
+    pub quorum: Option<usize>,
+}
+
+impl Route {
+    fn matches(&self, id: &str, tee: Tee) -> bool {
+        self.tee == tee
+            && self
+                .id_prefix
+                .as_ref()
+                .is_none_or(|prefix| id.starts_with(prefix))
+    }
+}
+
+#[derive(Debug)]
+pub struct Backends {
+    backends: BTreeMap<String, AttestationService>,
+    /// The first matching route applies.
+    routes: Vec<Route>,
+}
+
+impl Backends {
+    pub fn new(
+        default: AttestationService,
+        backends: Vec<(String, AttestationService)>,
+        routes: Vec<Route>,
+    ) -> Result<Self> {
+        let mut all = BTreeMap::from([(DEFAULT_BACKEND.to_string(), default)]);
+        for (name, backend) in backends {
+            if all.insert(name.clone(), backend).is_some() {
+                bail!("attestation backend {name} is defined twice");
+            }
+        }
+        for route in &routes {
+            if route.backends.is_empty() {
+                bail!("route for {:?} has no backends", route.tee);
+            }
+            if let Some(name) = route.backends.iter().find(|name| !all.contains_key(*name)) {
+                bail!("route for {:?} uses unknown backend {name}", route.tee);
+            }
+            // A backend listed twice would count twice towards the quorum.
+            let mut names = BTreeSet::new();
+            if let Some(name) = route.backends.iter().find(|name| !names.insert(*name)) {
+                bail!("route for {:?} lists backend {name} twice", route.tee);
+            }
+            if let Some(quorum) = route.quorum {
+                if quorum == 0 || quorum > route.backends.len() {
+                    bail!(
+                        "route for {:?} needs a quorum between 1 and {}",
+                        route.tee,
+                        route.backends.len()
+                    );
+                }
+            }
+        }
+
+        Ok(Self {
+            backends: all,
+            routes,
+        })
+    }
+
+    /// Verify `evidence` of `id` with the backends routed to, and return the
+    /// attestation token of the first one that accepted it.
+    pub async fn verify(
+        &self,
+        id: &str,
+        evidence: &str,
+        policy_ids: Vec<&str>,
+        nonce: &str,
+        csr: &str,
+        tee: Tee,
+    ) -> Result<String> {
+        let default = [DEFAULT_BACKEND.to_string()];
+        let route = self.routes.iter().find(|route| route.matches(id, tee));
+        let names = route.map_or(&default[..], |route| &route.backends[..]);
+        let verify =
+            |name: &str| self.backends[name].verify(evidence, policy_ids.clone(), nonce, csr, tee);
+
+        let Some(quorum) = route.and_then(|route| route.quorum) else {
+            let mut unavailable = None;
+            for name in names {
+                match verify(name).await {
+                    Err(e) if e.is::<Unavailable>() => {
+                        warn!("attestation backend {name}: {e:#}");
+                        unavailable = Some(e);
+                    }
+                    result => return result,
+                }
+            }
+            return Err(unavailable.expect("routes have backends"));
+        };
+
+        let results = join_all(names.iter().map(|name| verify(name))).await;
+        let mut accepted = Vec::new();
+        let mut unavailable = 0;
+        for (name, result) in names.iter().zip(results) {
+            match result {
+                Ok(token) => accepted.push(token),
+                Err(e) => {
+                    warn!("attestation backend {name}: {e:#}");
+                    if e.is::<Unavailable>() {
+                        unavailable += 1;
+                    }
+                }
+            }
+        }
+        if accepted.len() >= quorum {
+            return Ok(accepted.remove(0));
+        }
+
+        let summary = format!(
+            "{} of {} backends accepted the evidence, {quorum} required",
+            accepted.len(),
+            names.len()
+        );
+        // Could have reached the quorum with the backends that did not answer.
+        if accepted.len() + unavailable >= quorum {
+            return Err(anyhow!(Unavailable(summary)));
+        }
+        bail!("attestation failed, {summary}")
+    }
+
+    /// Probe every backend, see [`AttestationService::probe`], and check
+    /// that every route can still verify evidence: that its quorum, or for
+    /// failover one, of its backends is reachable. Evidence that no route
+    /// matches needs the default backend.
+    pub async fn probe(&self) -> Probe<'_> {
+        let results = join_all(self.backends.values().map(|backend| backend.probe())).await;
+        let backends: Vec<(&str, Result<()>)> = self
+            .backends
+            .keys()
+            .map(|name| &name[..])
+            .zip(results)
+            .collect();
+        let reachable = |name: &str| {
+            backends
+                .iter()
+                .any(|(backend, result)| *backend == name && result.is_ok())
+        };
+
+        let mut unserved = Vec::new();
+        for route in &self.routes {
+            let required = route.quorum.unwrap_or(1);
+            let available = route.backends.iter().filter(|name| reachable(name)).count();
+            if available < required {
+                let prefix = route
+                    .id_prefix
+                    .as_ref()
+                    .map(|prefix| format!(" and {prefix}"))
+                    .unwrap_or_default();
+                unserved.push(format!(
+                    "route for {:?}{prefix} has {available} of {} backends reachable, {required} required",
+                    route.tee,
+                    route.backends.len()
+                ));
+            }
+        }
+        if !reachable(DEFAULT_BACKEND) {
+            unserved.push(format!("backend {DEFAULT_BACKEND} is not reachable"));
+        }
+
+        let routes = match unserved.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow!(unserved.join("; "))),
+        };
+        Probe { backends, routes }
+    }
+}
+
+/// The result of [`Backends::probe`].
+pub struct Probe<'a> {
+    /// Whether each backend is reachable, by name.
+    pub backends: Vec<(&'a str, Result<()>)>,
+    /// Whether every route has enough reachable backends.
+    pub routes: Result<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::Mock;
+
+    fn backends(default: Mock, others: Vec<(&str, Mock)>, routes: Vec<Route>) -> Backends {
+        Backends::new(
+            AttestationService::Mock(default),
+            others
+                .into_iter()
+                .map(|(name, mock)| (name.to_string(), AttestationService::Mock(mock)))
+                .collect(),
+            routes,
+        )
+        .unwrap()
+    }
+
+    fn route(id_prefix: Option<&str>, backends: &[&str], quorum: Option<usize>) -> Route {
+        Route {
+            tee: Tee::Tdx,
+            id_prefix: id_prefix.map(str::to_string),
+            backends: backends.iter().map(|name| name.to_string()).collect(),
+            quorum,
+        }
+    }
+
+    async fn verify(backends: &Backends, id: &str, tee: Tee) -> Result<String> {
+        backends
+            .verify(id, "evidence", vec![], "nonce", "csr", tee)
+            .await
+    }
+
+    #[test]
+    fn illegal_routes() {
+        let new = |routes| {
+            Backends::new(
+                AttestationService::Mock(Mock::Accept("default")),
+                vec![("a".to_string(), AttestationService::Mock(Mock::Accept("a")))],
+                routes,
+            )
+        };
+        assert!(new(vec![route(None, &[], None)]).is_err());
+        assert!(new(vec![route(None, &["b"], None)]).is_err());
+        assert!(new(vec![route(None, &["a"], Some(0))]).is_err());
+        assert!(new(vec![route(None, &["a"], Some(2))]).is_err());
+        assert!(new(vec![route(None, &["a", "a"], Some(2))]).is_err());
+        assert!(new(vec![route(None, &["a", "a"], None)]).is_err());
+        assert!(new(vec![route(None, &["a", "default"], Some(2))]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn first_matching_route() {
+        let backends = backends(
+            Mock::Accept("default"),
+            vec![("a", Mock::Accept("a")), ("b", Mock::Accept("b"))],
+            vec![
+                route(Some("spiffe://high/"), &["a"], None),
+                route(None, &["b"], None),
+            ],
+        );
+        assert_eq!(
+            verify(&backends, "spiffe://high/w", Tee::Tdx)
+                .await
+                .unwrap(),
+            "a"
+        );
+        assert_eq!(
+            verify(&backends, "spiffe://w", Tee::Tdx).await.unwrap(),
+            "b"
+        );
+        assert_eq!(
+            verify(&backends, "spiffe://w", Tee::Sample).await.unwrap(),
+            "default"
+        );
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let available = backends(
+            Mock::Accept("default"),
+            vec![("a", Mock::Unavailable), ("b", Mock::Accept("b"))],
+            vec![route(None, &["a", "b"], None)],
+        );
+        assert_eq!(verify(&available, "w", Tee::Tdx).await.unwrap(), "b");
+
+        // A rejection is final, the next backend is not asked.
+        let rejecting = backends(
+            Mock::Accept("default"),
+            vec![("a", Mock::Reject), ("b", Mock::Accept("b"))],
+            vec![route(None, &["a", "b"], None)],
+        );
+        let e = verify(&rejecting, "w", Tee::Tdx).await.unwrap_err();
+        assert!(!e.is::<Unavailable>());
+
+        let down = backends(
+            Mock::Accept("default"),
+            vec![("a", Mock::Unavailable), ("b", Mock::Unavailable)],
+            vec![route(None, &["a", "b"], None)],
+        );
+        let e = verify(&down, "w", Tee::Tdx).await.unwrap_err();
+        assert!(e.is::<Unavailable>());
+    }
+
+    #[tokio::test]
+    async fn quorum() {
+        let quorum = |a, b, c| {
+            backends(
+                Mock::Accept("default"),
+                vec![("a", a), ("b", b), ("c", c)],
+                vec![route(None, &["a", "b", "c"], Some(2))],
+            )
+        };
+
+        let accepted = quorum(Mock::Reject, Mock::Accept("b"), Mock::Accept("c"));
+        assert_eq!(verify(&accepted, "w", Tee::Tdx).await.unwrap(), "b");
+
+        // The unavailable backend could still have made the quorum.
+        let possible = quorum(Mock::Reject, Mock::Accept("b"), Mock::Unavailable);
+        let e = verify(&possible, "w", Tee::Tdx).await.unwrap_err();
+        assert!(e.is::<Unavailable>());
+
+        let rejected = quorum(Mock::Reject, Mock::Reject, Mock::Unavailable);
+        let e = verify(&rejected, "w", Tee::Tdx).await.unwrap_err();
+        assert!(!e.is::<Unavailable>());
+    }
+
+    #[tokio::test]
+    async fn probe_routes() {
+        let routes = || {
+            vec![
+                route(Some("spiffe://high/"), &["a", "b"], Some(2)),
+                route(None, &["a", "b"], None),
+            ]
+        };
+
+        let all = backends(
+            Mock::Accept("default"),
+            vec![("a", Mock::Accept("a")), ("b", Mock::Accept("b"))],
+            routes(),
+        );
+        let probe = all.probe().await;
+        assert!(probe.routes.is_ok());
+        assert!(probe.backends.iter().all(|(_, result)| result.is_ok()));
+
+        // Failover still works, but the quorum can not be reached.
+        let one = backends(
+            Mock::Accept("default"),
+            vec![("a", Mock::Accept("a")), ("b", Mock::Unavailable)],
+            routes(),
+        );
+        let probe = one.probe().await;
+        let e = probe.routes.unwrap_err().to_string();
+        assert!(e.contains("spiffe://high/ has 1 of 2 backends reachable, 2 required"));
+        assert!(!e.contains("; "));
+        assert!(probe
+            .backends
+            .iter()
+            .any(|(name, result)| *name == "b" && result.is_err()));
+
+        let failover = backends(
+            Mock::Accept("default"),
+            vec![("a", Mock::Accept("a")), ("b", Mock::Unavailable)],
+            vec![route(None, &["a", "b"], None)],
+        );
+        assert!(failover.probe().await.routes.is_ok());
+
+        let none = backends(
+            Mock::Unavailable,
+            vec![("a", Mock::Accept("a")), ("b", Mock::Accept("b"))],
+            vec![route(None, &["a", "b"], None)],
+        );
+        assert!(none.probe().await.routes.is_err());
+    }
+}
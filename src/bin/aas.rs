@@ -4,27 +4,40 @@
 
 mod api;
 mod configs;
+mod telemetry;
+mod tls;
 
-use std::{any::Any, io::Cursor, sync::Arc};
+use std::{any::Any, path::PathBuf, sync::Arc, time::Duration, time::SystemTime};
 
-use actix_tls::accept::rustls_0_21::{reexports::ServerConfig, TlsStream};
+use actix_tls::accept::rustls_0_21::TlsStream;
 use actix_web::{
-    dev::Extensions,
+    dev::{Extensions, ServerHandle, Service},
     rt::net::TcpStream,
     web::{self, Data},
     App, HttpServer,
 };
 use anyhow::Result;
-use api::{attest, auth, get_resource, register};
-use attestation_auth_server::builder::ServerBuilder;
+use api::{
+    acme_account, acme_authorization, acme_certificate, acme_challenge, acme_directory,
+    acme_finalize, acme_new_account, acme_new_nonce, acme_new_order, acme_order, add_ca_key,
+    attest, auth, ca_certs_der, ca_certs_pem, configuration, est_cacerts, est_simpleenroll,
+    est_simplereenroll, get_resource, healthz, jwks, ledger, list_ca_keys, metrics, promote_ca_key,
+    readyz, register, renew, retire_ca_key, verify_ledger, version, AdminAuth,
+};
+use attestation_auth_server::{
+    attestation::routing::Backends,
+    builder::ServerBuilder,
+    ca::rotation::KeyRing,
+    ledger::Ledger,
+    server::{Discovery, Server},
+};
 use clap::Parser;
 use configs::Config;
-use log::info;
-use rustls::{
-    server::AllowAnyAnonymousOrAuthenticatedClient, Certificate, PrivateKey, RootCertStore,
-};
-use rustls_pemfile::pkcs8_private_keys;
+use log::{info, warn};
 use strum::{AsRefStr, EnumString};
+use tls::{ReloadableTls, TlsMaterial};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::Instrument;
 
 /// AAS command-line arguments.
 #[derive(Debug, Parser)]
@@ -44,8 +57,89 @@ enum WebApi {
     #[strum(serialize = "/rcar/attest")]
     Attest,
 
+    #[strum(serialize = "/rcar/renew")]
+    Renew,
+
     #[strum(serialize = "/register")]
     Register,
+
+    #[strum(serialize = "/.well-known/aas-configuration")]
+    Configuration,
+
+    #[strum(serialize = "/.well-known/aas/ca.pem")]
+    CaCertsPem,
+
+    #[strum(serialize = "/.well-known/aas/ca.der")]
+    CaCertsDer,
+
+    #[strum(serialize = "/.well-known/aas/jwks.json")]
+    Jwks,
+
+    #[strum(serialize = "/.well-known/est/cacerts")]
+    EstCaCerts,
+
+    #[strum(serialize = "/.well-known/est/simpleenroll")]
+    EstSimpleEnroll,
+
+    #[strum(serialize = "/.well-known/est/simplereenroll")]
+    EstSimpleReenroll,
+
+    #[strum(serialize = "/acme/directory")]
+    AcmeDirectory,
+
+    #[strum(serialize = "/acme/new-nonce")]
+    AcmeNewNonce,
+
+    #[strum(serialize = "/acme/new-account")]
+    AcmeNewAccount,
+
+    #[strum(serialize = "/acme/account/{id}")]
+    AcmeAccount,
+
+    #[strum(serialize = "/acme/new-order")]
+    AcmeNewOrder,
+
+    #[strum(serialize = "/acme/order/{id}")]
+    AcmeOrder,
+
+    #[strum(serialize = "/acme/authz/{id}")]
+    AcmeAuthorization,
+
+    #[strum(serialize = "/acme/chall/{id}")]
+    AcmeChallenge,
+
+    #[strum(serialize = "/acme/order/{id}/finalize")]
+    AcmeFinalize,
+
+    #[strum(serialize = "/acme/cert/{id}")]
+    AcmeCertificate,
+
+    #[strum(serialize = "/metrics")]
+    Metrics,
+
+    #[strum(serialize = "/healthz")]
+    Healthz,
+
+    #[strum(serialize = "/readyz")]
+    Readyz,
+
+    #[strum(serialize = "/version")]
+    Version,
+
+    #[strum(serialize = "/admin/ca/keys")]
+    CaKeys,
+
+    #[strum(serialize = "/admin/ca/keys/{id}/promote")]
+    PromoteCaKey,
+
+    #[strum(serialize = "/admin/ca/keys/{id}/retire")]
+    RetireCaKey,
+
+    #[strum(serialize = "/admin/ledger")]
+    Ledger,
+
+    #[strum(serialize = "/admin/ledger/verify")]
+    VerifyLedger,
 }
 
 fn get_client_cert(connection: &dyn Any, data: &mut Extensions) {
@@ -58,85 +152,302 @@ fn get_client_cert(connection: &dyn Any, data: &mut Extensions) {
 
             data.insert(certs.first().unwrap().clone());
         }
-    } else if let Some(_) = connection.downcast_ref::<TcpStream>() {
+    } else if connection.downcast_ref::<TcpStream>().is_some() {
         info!("plaintext on_connect");
     } else {
         unreachable!("socket should be TLS or plaintext");
     }
 }
 
+/// Reload the HTTPS material, the CA keys and the attestation service from
+/// `config_path`. Nothing is changed if any of them fails to load. Returns the
+/// files to watch.
+async fn reload(config_path: &str, server: &Server, tls: &ReloadableTls) -> Result<Vec<PathBuf>> {
+    let config = Config::try_from(config_path)?;
+    let files = watched_files(config_path, &config);
+    let tls_material = TlsMaterial::load(&config)?;
+    let mut backends = Vec::new();
+    for (name, backend) in config.attestation_backends {
+        backends.push((name, backend.try_into()?));
+    }
+    let attestation_service = Backends::new(
+        config.attestation_service.try_into()?,
+        backends,
+        config.attestation_routes,
+    )?;
+    let mut ca_keys = Vec::new();
+    for key in config.ca_keys {
+        ca_keys.push((key.id, key.state, key.ca.try_into()?));
+    }
+    let ca = KeyRing::with_keys(config.ca.try_into()?, ca_keys)?;
+
+    server.reload(ca, attestation_service).await?;
+    tls.swap(tls_material);
+    tls.trust_ca(server.trust_bundle_der().await?);
+    Ok(files)
+}
+
+/// The config file and the files its `file:` values were read from.
+fn watched_files(config_path: &str, config: &Config) -> Vec<PathBuf> {
+    std::iter::once(PathBuf::from(config_path))
+        .chain(config.files.iter().cloned())
+        .collect()
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Reload the config on SIGHUP, or when one of `files` changes if `interval`
+/// is given.
+async fn watch_config(
+    config_path: String,
+    mut files: Vec<PathBuf>,
+    interval: Option<u64>,
+    server: Arc<Server>,
+    tls: Arc<ReloadableTls>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("failed to watch SIGHUP, the config will not be reloaded: {e}");
+            return;
+        }
+    };
+    let mut ticker = interval.map(|secs| tokio::time::interval(Duration::from_secs(secs)));
+    let mut modified = modified_times(&files);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("SIGHUP received, reload {config_path}");
+            }
+            _ = async {
+                match &mut ticker {
+                    Some(ticker) => {
+                        ticker.tick().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            } => {
+                if modified_times(&files) == modified {
+                    continue;
+                }
+                info!("{config_path} or a file it refers to changed, reload it");
+            }
+        }
+
+        match reload(&config_path, &server, &tls).await {
+            Ok(reloaded) => files = reloaded,
+            Err(e) => warn!("reload failed, keep the current configuration: {e:#}"),
+        }
+        modified = modified_times(&files);
+    }
+}
+
+/// On SIGTERM or SIGINT, refuse new handshakes and wait at most `timeout`
+/// for the pending ones, then stop the HTTP server once the requests in
+/// progress are served.
+async fn shutdown_on_signal(handle: ServerHandle, server: Arc<Server>, timeout: Duration) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("failed to watch SIGTERM: {e}");
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("SIGTERM received, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("SIGINT received, shutting down"),
+    }
+
+    match server.drain(timeout).await {
+        Ok(true) => info!("no pending handshakes"),
+        Ok(false) => warn!("shutdown timeout reached with pending handshakes"),
+        Err(e) => warn!("failed to wait for pending handshakes: {e:#}"),
+    }
+    handle.stop(true).await;
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-
     // Parse CLI parameters
     let cli = Cli::parse();
     let config = Config::try_from(&cli.config_file[..])?;
+    let tracer_provider = telemetry::init(config.tracing.as_ref())?;
+    let tls = ReloadableTls::new(TlsMaterial::load(&config)?);
+    let config_watch_interval = config.config_watch_interval;
+    let watched_files = watched_files(&cli.config_file, &config);
 
     // Initialize backend attestation service
     let attestation_service = config.attestation_service.try_into()?;
     let ca = config.ca.try_into()?;
+    let session_store = config.session_store.try_into()?;
+
+    let mut builder = ServerBuilder::new();
+    if let Some(token) = config.token {
+        builder = builder.with_token_issuer(token.into_issuer()?);
+    }
+    for key in config.ca_keys {
+        builder = builder.with_ca_key(key.id, key.state, key.ca.try_into()?);
+    }
+    for (name, backend) in config.attestation_backends {
+        builder = builder.with_attestation_backend(name, backend.try_into()?);
+    }
+    for route in config.attestation_routes {
+        builder = builder.with_attestation_route(route);
+    }
 
     let server = Arc::new(
-        ServerBuilder::new()
+        builder
             .with_attestation_service(attestation_service)
             .with_attestation_timeout(config.attestation_timeout)
+            .with_cert_ttl(config.cert_ttl)
+            .with_renewal_window(config.renewal_window)
             .with_ca(ca)
+            .with_session_store(session_store)
+            .with_ledger(Ledger::new(config.ledger.hash_chain))
+            .with_revoke_renewed(config.revoke_renewed_certs)
+            .with_limits(config.limits)
             .build()?,
     );
 
-    let server = Data::new(server);
+    tls.trust_ca(server.trust_bundle_der().await?);
 
-    // Initialize TLS set-ups
-    // HTTPS public key cert
-    let mut cursor = Cursor::new(config.https_cert);
-    let https_cert_chain = rustls_pemfile::certs(&mut cursor)?
-        .into_iter()
-        .map(Certificate)
-        .collect();
-
-    // HTTPS private key
-    let mut cursor = Cursor::new(config.https_private_key);
-
-    let mut https_key: Vec<PrivateKey> = pkcs8_private_keys(&mut cursor)?
-        .into_iter()
-        .map(PrivateKey)
-        .collect();
-
-    // mTLS client key root cert
-    let mut cursor = Cursor::new(config.client_root_ca_cert);
-    let mtls_cert_chain = rustls_pemfile::certs(&mut cursor)?;
-
-    let mut client_root_cert_store = RootCertStore::empty();
-    let (_, _skip) = client_root_cert_store.add_parsable_certificates(&mtls_cert_chain);
-    let mtls_verifier = Arc::new(AllowAnyAnonymousOrAuthenticatedClient::new(
-        client_root_cert_store,
+    tokio::spawn(watch_config(
+        cli.config_file,
+        watched_files,
+        config_watch_interval,
+        server.clone(),
+        tls.clone(),
     ));
-    let tls_config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_client_cert_verifier(mtls_verifier)
-        .with_single_cert_with_ocsp_and_sct(
-            https_cert_chain,
-            https_key.remove(0),
-            Vec::new(),
-            Vec::new(),
-        )?;
-
-    HttpServer::new(move || {
+    let tls_config = tls.server_config();
+    let aas = server.clone();
+    let server = Data::new(server);
+    let reloadable_tls = Data::new(tls.clone());
+    let admin = match config.admin {
+        Some(admin) => Some(Data::new(AdminAuth::new(&admin.token)?)),
+        None => {
+            info!("no [admin] configured, the /admin endpoints are not served");
+            None
+        }
+    };
+
+    let http_server = HttpServer::new(move || {
         App::new()
+            .wrap_fn(|request, service| {
+                let span = telemetry::request_span(request.request());
+                service.call(request).instrument(span)
+            })
             .service(web::resource(WebApi::Auth.as_ref()).route(web::post().to(auth)))
             .service(web::resource(WebApi::Attest.as_ref()).route(web::post().to(attest)))
+            .service(web::resource(WebApi::Renew.as_ref()).route(web::post().to(renew)))
             .service(web::resource(WebApi::Register.as_ref()).route(web::post().to(register)))
+            .service(
+                web::resource(WebApi::Configuration.as_ref()).route(web::get().to(configuration)),
+            )
+            .service(web::resource(WebApi::CaCertsPem.as_ref()).route(web::get().to(ca_certs_pem)))
+            .service(web::resource(WebApi::CaCertsDer.as_ref()).route(web::get().to(ca_certs_der)))
+            .service(web::resource(WebApi::Jwks.as_ref()).route(web::get().to(jwks)))
+            .service(web::resource(WebApi::EstCaCerts.as_ref()).route(web::get().to(est_cacerts)))
+            .service(
+                web::resource(WebApi::EstSimpleEnroll.as_ref())
+                    .route(web::post().to(est_simpleenroll)),
+            )
+            .service(
+                web::resource(WebApi::EstSimpleReenroll.as_ref())
+                    .route(web::post().to(est_simplereenroll)),
+            )
+            .service(
+                web::resource(WebApi::AcmeDirectory.as_ref()).route(web::get().to(acme_directory)),
+            )
+            .service(
+                web::resource(WebApi::AcmeNewNonce.as_ref())
+                    .route(web::head().to(acme_new_nonce))
+                    .route(web::get().to(acme_new_nonce)),
+            )
+            .service(
+                web::resource(WebApi::AcmeNewAccount.as_ref())
+                    .route(web::post().to(acme_new_account)),
+            )
+            .service(
+                web::resource(WebApi::AcmeAccount.as_ref()).route(web::post().to(acme_account)),
+            )
+            .service(
+                web::resource(WebApi::AcmeNewOrder.as_ref()).route(web::post().to(acme_new_order)),
+            )
+            .service(web::resource(WebApi::AcmeOrder.as_ref()).route(web::post().to(acme_order)))
+            .service(
+                web::resource(WebApi::AcmeAuthorization.as_ref())
+                    .route(web::post().to(acme_authorization)),
+            )
+            .service(
+                web::resource(WebApi::AcmeChallenge.as_ref()).route(web::post().to(acme_challenge)),
+            )
+            .service(
+                web::resource(WebApi::AcmeFinalize.as_ref()).route(web::post().to(acme_finalize)),
+            )
+            .service(
+                web::resource(WebApi::AcmeCertificate.as_ref())
+                    .route(web::post().to(acme_certificate)),
+            )
+            .service(web::resource(WebApi::Metrics.as_ref()).route(web::get().to(metrics)))
+            .service(web::resource(WebApi::Healthz.as_ref()).route(web::get().to(healthz)))
+            .service(web::resource(WebApi::Readyz.as_ref()).route(web::get().to(readyz)))
+            .service(web::resource(WebApi::Version.as_ref()).route(web::get().to(version)))
+            .configure(|cfg| {
+                let Some(admin) = &admin else {
+                    return;
+                };
+                cfg.app_data(web::Data::clone(admin))
+                    .service(
+                        web::resource(WebApi::CaKeys.as_ref())
+                            .route(web::get().to(list_ca_keys))
+                            .route(web::post().to(add_ca_key)),
+                    )
+                    .service(
+                        web::resource(WebApi::PromoteCaKey.as_ref())
+                            .route(web::post().to(promote_ca_key)),
+                    )
+                    .service(
+                        web::resource(WebApi::RetireCaKey.as_ref())
+                            .route(web::post().to(retire_ca_key)),
+                    )
+                    .service(web::resource(WebApi::Ledger.as_ref()).route(web::get().to(ledger)))
+                    .service(
+                        web::resource(WebApi::VerifyLedger.as_ref())
+                            .route(web::get().to(verify_ledger)),
+                    );
+            })
             .service(
                 web::resource("resource/{repository}/{type}/{tag}")
                     .route(web::get().to(get_resource)),
             )
             .app_data(web::Data::clone(&server))
+            .app_data(web::Data::clone(&reloadable_tls))
     })
     .on_connect(get_client_cert)
+    .disable_signals()
+    .shutdown_timeout(config.shutdown_timeout)
     .bind_rustls_021((config.socket.ip(), config.socket.port()), tls_config)?
-    .run()
-    .await?;
+    .run();
 
+    tokio::spawn(shutdown_on_signal(
+        http_server.handle(),
+        aas.clone(),
+        Duration::from_secs(config.shutdown_timeout),
+    ));
+    http_server.await?;
+
+    aas.flush().await?;
+    if let Some(provider) = tracer_provider {
+        provider.shutdown()?;
+    }
     Ok(())
 }
@@ -4,19 +4,39 @@
 
 use std::sync::Arc;
 
-use actix_web::{body::BoxBody, web, HttpRequest, HttpResponse, ResponseError};
-use anyhow::{anyhow, Context};
+use actix_web::{
+    body::BoxBody,
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse, ResponseError,
+};
+use anyhow::{anyhow, bail, Context};
 use attestation_auth_server::{
-    server::{AccessControl, Server, RCAR},
-    session::Attestation,
+    acme::{AcmeBody, AcmeReply, Jws, Problem},
+    attestation::Unavailable,
+    ca::{profile::IssuancePolicy, serial_hex},
+    est::certs_only,
+    ledger::LedgerFilter,
+    limit::RateLimited,
+    protocol::InvalidRequest,
+    server::{
+        AccessControl, Acme, Audit, CaAdmin, ClientCert, Discovery, Est, Monitoring, Server,
+        ShuttingDown, RCAR,
+    },
+    session::{Attestation, Response, TeePolicy},
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::DateTime;
 use kbs_types::Request;
 use log::{debug, info, warn};
 use rustls::Certificate;
 use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use strum::AsRefStr;
 use thiserror::Error;
 
+use crate::{configs::CaConfig, tls::ReloadableTls, WebApi};
+
 #[derive(Error, Debug, AsRefStr)]
 pub enum Error {
     #[error("An internal error occured: {0}")]
@@ -25,9 +45,31 @@ pub enum Error {
 
 impl ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
-        let body = format!("{self:#?}");
+        let mut body = format!("{self:#?}");
 
         let mut res = match self {
+            Error::InternalError(e) if e.is::<ShuttingDown>() || e.is::<Unavailable>() => {
+                HttpResponse::ServiceUnavailable()
+            }
+            Error::InternalError(e) if e.is::<InvalidRequest>() => {
+                // Tell the client what to fix, e.g. the supported versions.
+                body = e.to_string();
+                HttpResponse::BadRequest()
+            }
+            Error::InternalError(e) if e.is::<RateLimited>() => {
+                let mut res = HttpResponse::TooManyRequests();
+                if let Some(limited) = e.downcast_ref::<RateLimited>() {
+                    let secs = limited.retry_after.as_secs_f64().ceil() as u64;
+                    res.insert_header((header::RETRY_AFTER, secs.max(1)));
+                }
+                res
+            }
+            Error::InternalError(e) if e.is::<Unauthorized>() => {
+                body = e.to_string();
+                let mut res = HttpResponse::Unauthorized();
+                res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+                res
+            }
             Error::InternalError(_) => HttpResponse::InternalServerError(),
             // _ => HttpResponse::NotImplemented(),
         };
@@ -39,24 +81,97 @@ impl ResponseError for Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// An admin request without the admin token.
+#[derive(Error, Debug)]
+#[error("admin authentication required")]
+pub struct Unauthorized;
+
+/// Authenticates the requests to the `/admin` endpoints by a bearer token.
+pub struct AdminAuth {
+    /// Digest of the token, so that comparing it does not leak the token
+    /// through timing.
+    digest: Vec<u8>,
+}
+
+impl AdminAuth {
+    pub fn new(token: &str) -> anyhow::Result<Self> {
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("the admin token must not be empty");
+        }
+
+        Ok(Self {
+            digest: Sha256::digest(token).to_vec(),
+        })
+    }
+
+    fn authorize(&self, request: &HttpRequest) -> Result<()> {
+        match bearer_token(request) {
+            std::result::Result::Ok(Some(token)) if Sha256::digest(token)[..] == self.digest => {
+                Ok(())
+            }
+            _ => Err(anyhow!(Unauthorized).into()),
+        }
+    }
+}
+
+/// The address of the TCP peer of `request`, or empty if unknown. Forwarded
+/// headers are not trusted.
+fn source(request: &HttpRequest) -> String {
+    request
+        .peer_addr()
+        .map(|peer| peer.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// Apply the per-source limits to the TCP peer of `request`.
+async fn limit_source(aas: &Server, request: &HttpRequest) -> anyhow::Result<()> {
+    if request.peer_addr().is_some() {
+        aas.limit_source(&source(request)).await?;
+    }
+
+    Ok(())
+}
+
 pub async fn auth(
+    http_request: HttpRequest,
     request: web::Json<Request>,
     aas: web::Data<Arc<Server>>,
 ) -> Result<HttpResponse> {
     info!("new RCAR Request.");
 
-    let challenge = aas.request(request.0).await?;
+    limit_source(&aas, &http_request).await?;
+    let challenge = aas.request(request.0, &source(&http_request)).await?;
     info!("response challenge {challenge:?}");
     Ok(HttpResponse::Ok().json(challenge))
 }
 
+/// Start renewing the mTLS client certificate. The evidence is submitted
+/// via `/rcar/attest` as in the first handshake.
+pub async fn renew(
+    http_request: HttpRequest,
+    request: web::Json<Request>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    info!("new RCAR Renewal.");
+
+    limit_source(&aas, &http_request).await?;
+    let cert = client_cert(&http_request)?;
+    let challenge = aas.renew(request.0, &cert, &source(&http_request)).await?;
+    Ok(HttpResponse::Ok().json(challenge))
+}
+
 pub async fn attest(
+    http_request: HttpRequest,
     attestation: web::Json<Attestation>,
     aas: web::Data<Arc<Server>>,
 ) -> Result<HttpResponse> {
     info!("new RCAR Attestation.");
 
-    let response = aas.attestation(attestation.0).await?;
+    limit_source(&aas, &http_request).await?;
+    let response = aas
+        .attestation(attestation.0, &source(&http_request))
+        .await?;
     Ok(HttpResponse::Ok().json(response))
 }
 
@@ -65,18 +180,46 @@ pub struct Register {
     id: String,
     policy_ids: Vec<String>,
     allowed_resources: Vec<String>,
+    #[serde(flatten)]
+    issuance: IssuancePolicy,
+    #[serde(flatten)]
+    tees: TeePolicy,
+}
+
+/// With `[admin]`, only the admin may register identities. Without it, only
+/// identities getting the default client certificates can be registered, as
+/// others could get certificates for any DNS name or IP address.
+fn authorize_registration(
+    admin: Option<&AdminAuth>,
+    request: &HttpRequest,
+    register: &Register,
+) -> Result<()> {
+    match admin {
+        Some(admin) => admin.authorize(request),
+        None if register.issuance.is_default() => Ok(()),
+        None => Err(anyhow!(Unauthorized).into()),
+    }
 }
 
 pub async fn register(
+    http_request: HttpRequest,
     req: web::Json<Register>,
     aas: web::Data<Arc<Server>>,
+    admin: Option<web::Data<AdminAuth>>,
 ) -> Result<HttpResponse> {
     info!("new instance registering.");
+    authorize_registration(
+        admin.as_ref().map(|admin| admin.get_ref()),
+        &http_request,
+        &req,
+    )?;
 
     aas.register_user(
         &req.id,
         req.policy_ids.clone(),
         req.allowed_resources.clone(),
+        req.issuance.clone(),
+        req.tees.clone(),
     )
     .await?;
 
@@ -89,13 +232,6 @@ pub async fn get_resource(
     aas: web::Data<Arc<Server>>,
 ) -> Result<HttpResponse> {
     info!("get resource ...");
-    let Some(client_cert) = request.conn_data::<Certificate>() else {
-        Err(anyhow!("No client TLS cert"))?
-    };
-
-    let (_, client_cert) = x509_parser::parse_x509_certificate(client_cert.as_ref())
-        .context("Parse mTLS client cert failed")?;
-
     let repository_name = request.match_info().get("repository").unwrap_or("default");
     let resource_type = request
         .match_info()
@@ -107,6 +243,48 @@ pub async fn get_resource(
         .ok_or(anyhow!("no `tag` in url"))?;
 
     let rid = format!("{repository_name}/{resource_type}/{resource_tag}");
+    let id = match bearer_token(&request)? {
+        Some(token) => aas.verify_token(token).await?.sub,
+        None => {
+            let cert = client_cert(&request)?;
+            if aas.is_revoked(&cert.serial).await? {
+                Err(anyhow!(
+                    "client certificate {} has been revoked",
+                    cert.serial
+                ))?;
+            }
+            cert.id
+        }
+    };
+
+    let resource = aas.get_resource(&rid, &id).await?;
+    Ok(HttpResponse::Ok().body(resource))
+}
+
+/// Get the token of an `Authorization: Bearer` header, if any.
+fn bearer_token(request: &HttpRequest) -> anyhow::Result<Option<&str>> {
+    let Some(header) = request.headers().get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+
+    let token = header
+        .to_str()
+        .context("illegal Authorization header")?
+        .strip_prefix("Bearer ")
+        .ok_or(anyhow!("Authorization header is not a Bearer token"))?;
+    Ok(Some(token.trim()))
+}
+
+/// Get the identity from the URI SAN of the mTLS client cert, together with
+/// the serial number and the expiry of the cert.
+fn client_cert(request: &HttpRequest) -> anyhow::Result<ClientCert> {
+    let Some(client_cert) = request.conn_data::<Certificate>() else {
+        bail!("No client TLS cert");
+    };
+
+    let (_, client_cert) = x509_parser::parse_x509_certificate(client_cert.as_ref())
+        .context("Parse mTLS client cert failed")?;
+
     let id = match client_cert
         .subject_alternative_name()
         .context("get SAN extension")?
@@ -114,16 +292,501 @@ pub async fn get_resource(
         .value
         .general_names
         .iter()
-        .find(|it| match it {
-            x509_parser::extensions::GeneralName::URI(_) => true,
-            _ => false,
-        })
+        .find(|it| matches!(it, x509_parser::extensions::GeneralName::URI(_)))
         .ok_or(anyhow!("No SAN extension as URI"))?
     {
         x509_parser::extensions::GeneralName::URI(id) => id,
-        _ => Err(anyhow!("illegal SAN, should be URI"))?,
+        _ => bail!("illegal SAN, should be URI"),
     };
 
-    let resource = aas.get_resource(&rid, id).await?;
-    Ok(HttpResponse::Ok().body(resource))
+    Ok(ClientCert {
+        id: id.to_string(),
+        serial: serial_hex(client_cert.raw_serial()),
+        not_after: DateTime::from_timestamp(client_cert.validity().not_after.timestamp(), 0)
+            .context("illegal notAfter of client cert")?,
+    })
+}
+
+/// Scheme and authority the client reached the server at.
+fn base_url(request: &HttpRequest) -> String {
+    let info = request.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+/// Tell relying parties where to find the endpoints and verification material.
+pub async fn configuration(
+    request: HttpRequest,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let base = base_url(&request);
+    let url = |api: WebApi| format!("{base}{}", api.as_ref());
+
+    let mut configuration = json!({
+        "rcar_auth_endpoint": url(WebApi::Auth),
+        "rcar_attest_endpoint": url(WebApi::Attest),
+        "ca_certs_pem_uri": url(WebApi::CaCertsPem),
+        "ca_certs_der_uri": url(WebApi::CaCertsDer),
+        "jwks_uri": url(WebApi::Jwks),
+        "acme_directory_uri": url(WebApi::AcmeDirectory),
+    });
+    if let Some((issuer, audience)) = aas.token_info().await {
+        configuration["issuer"] = issuer.into();
+        configuration["audience"] = audience.into();
+        configuration["token_signing_alg_values_supported"] = json!(["ES256"]);
+    }
+
+    Ok(HttpResponse::Ok().json(configuration))
+}
+
+pub async fn ca_certs_pem(aas: web::Data<Arc<Server>>) -> Result<HttpResponse> {
+    let bundle = aas.trust_bundle().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-pem-file")
+        .body(bundle))
+}
+
+/// The issuing CA certificate in DER. Use the PEM bundle to get the full chain.
+pub async fn ca_certs_der(aas: web::Data<Arc<Server>>) -> Result<HttpResponse> {
+    let issuing_ca = aas
+        .trust_bundle_der()
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("no CA certificate"))?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/pkix-cert")
+        .body(issuing_ca))
+}
+
+pub async fn jwks(aas: web::Data<Arc<Server>>) -> Result<HttpResponse> {
+    let jwks = aas.jwks().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/jwk-set+json")
+        .json(jwks))
+}
+
+pub async fn metrics(aas: web::Data<Arc<Server>>) -> Result<HttpResponse> {
+    let metrics = aas.metrics().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics))
+}
+
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+pub async fn readyz(aas: web::Data<Arc<Server>>) -> HttpResponse {
+    let readiness = aas.readiness().await;
+    if !readiness.ready {
+        warn!("not ready: {:?}", readiness.checks);
+        return HttpResponse::ServiceUnavailable().json(readiness);
+    }
+
+    HttpResponse::Ok().json(readiness)
+}
+
+pub async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+pub async fn list_ca_keys(
+    http_request: HttpRequest,
+    aas: web::Data<Arc<Server>>,
+    admin: web::Data<AdminAuth>,
+) -> Result<HttpResponse> {
+    admin.authorize(&http_request)?;
+    let keys = aas.list_ca_keys().await;
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+#[derive(Deserialize)]
+pub struct AddCaKey {
+    id: String,
+    ca: CaConfig,
+}
+
+pub async fn add_ca_key(
+    http_request: HttpRequest,
+    req: web::Json<AddCaKey>,
+    aas: web::Data<Arc<Server>>,
+    tls: web::Data<Arc<ReloadableTls>>,
+    admin: web::Data<AdminAuth>,
+) -> Result<HttpResponse> {
+    admin.authorize(&http_request)?;
+    info!("new CA key {}.", req.id);
+    let req = req.into_inner();
+    aas.add_ca_key(&req.id, req.ca.try_into()?).await?;
+    tls.trust_ca(aas.trust_bundle_der().await?);
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn promote_ca_key(
+    http_request: HttpRequest,
+    id: web::Path<String>,
+    aas: web::Data<Arc<Server>>,
+    tls: web::Data<Arc<ReloadableTls>>,
+    admin: web::Data<AdminAuth>,
+) -> Result<HttpResponse> {
+    admin.authorize(&http_request)?;
+    aas.promote_ca_key(&id).await?;
+    tls.trust_ca(aas.trust_bundle_der().await?);
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn retire_ca_key(
+    http_request: HttpRequest,
+    id: web::Path<String>,
+    aas: web::Data<Arc<Server>>,
+    tls: web::Data<Arc<ReloadableTls>>,
+    admin: web::Data<AdminAuth>,
+) -> Result<HttpResponse> {
+    admin.authorize(&http_request)?;
+    aas.retire_ca_key(&id).await?;
+    tls.trust_ca(aas.trust_bundle_der().await?);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+pub struct LedgerQuery {
+    /// Matching entries to skip.
+    #[serde(default)]
+    offset: u64,
+    /// Matching entries to return, at most [`MAX_LEDGER_LIMIT`].
+    #[serde(default = "default_ledger_limit")]
+    limit: u64,
+    /// Only return the entries of this identity.
+    id: Option<String>,
+    /// Only return the entry of this certificate serial.
+    serial: Option<String>,
+}
+
+fn default_ledger_limit() -> u64 {
+    100
+}
+
+/// Most ledger entries returned at once.
+const MAX_LEDGER_LIMIT: u64 = 1000;
+
+pub async fn ledger(
+    http_request: HttpRequest,
+    query: web::Query<LedgerQuery>,
+    aas: web::Data<Arc<Server>>,
+    admin: web::Data<AdminAuth>,
+) -> Result<HttpResponse> {
+    admin.authorize(&http_request)?;
+    let query = query.into_inner();
+    let filter = LedgerFilter {
+        id: query.id,
+        serial: query.serial,
+    };
+    let entries = aas
+        .ledger_entries(&filter, query.offset, query.limit.min(MAX_LEDGER_LIMIT))
+        .await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn verify_ledger(
+    http_request: HttpRequest,
+    aas: web::Data<Arc<Server>>,
+    admin: web::Data<AdminAuth>,
+) -> Result<HttpResponse> {
+    admin.authorize(&http_request)?;
+    let entries = aas.verify_ledger().await?;
+    Ok(HttpResponse::Ok().json(json!({ "verified_entries": entries })))
+}
+
+/// Reply a base64 encoded certs-only PKCS#7 as required by EST.
+fn est_certs_response(certs: &[Vec<u8>]) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/pkcs7-mime; smime-type=certs-only")
+        .insert_header(("Content-Transfer-Encoding", "base64"))
+        .body(STANDARD.encode(certs_only(certs)))
+}
+
+/// Decode the base64 encoded PKCS#10 body of an EST enrollment.
+fn est_csr(body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let body: Vec<u8> = body
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    STANDARD.decode(body).context("EST CSR is not base64")
+}
+
+/// The issued certificate followed by its chain, in DER.
+fn est_issued_certs(response: Response) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut certs = Vec::new();
+    for pem in std::iter::once(&response.cert.crt).chain(&response.cert.chain) {
+        for pem in x509_parser::pem::Pem::iter_from_buffer(pem.as_bytes()) {
+            certs.push(pem.context("illegal issued certificate PEM")?.contents);
+        }
+    }
+
+    Ok(certs)
+}
+
+pub async fn est_cacerts(aas: web::Data<Arc<Server>>) -> Result<HttpResponse> {
+    let certs = aas.trust_bundle_der().await?;
+    Ok(est_certs_response(&certs))
+}
+
+pub async fn est_simpleenroll(
+    request: HttpRequest,
+    body: web::Bytes,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    info!("new EST enrollment.");
+
+    limit_source(&aas, &request).await?;
+    let response = aas.enroll(&est_csr(&body)?, &source(&request)).await?;
+    Ok(est_certs_response(&est_issued_certs(response)?))
+}
+
+pub async fn est_simplereenroll(
+    request: HttpRequest,
+    body: web::Bytes,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    info!("new EST re-enrollment.");
+
+    limit_source(&aas, &request).await?;
+    let cert = client_cert(&request)?;
+    let response = aas
+        .reenroll(&est_csr(&body)?, &cert, &source(&request))
+        .await?;
+    Ok(est_certs_response(&est_issued_certs(response)?))
+}
+
+/// Reply an ACME request with a fresh nonce, reporting errors as problem
+/// documents so that clients can e.g. retry on `badNonce`.
+async fn acme_response(aas: &Server, reply: anyhow::Result<AcmeReply>) -> Result<HttpResponse> {
+    let nonce = aas.acme_nonce().await?;
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => {
+            let mut response = HttpResponse::build(StatusCode::OK);
+            let problem = if let Some(limited) = e.downcast_ref::<RateLimited>() {
+                let secs = limited.retry_after.as_secs_f64().ceil() as u64;
+                response.insert_header((header::RETRY_AFTER, secs.max(1)));
+                Problem::new("rateLimited", limited.to_string())
+            } else if e.is::<ShuttingDown>() || e.is::<Unavailable>() {
+                Problem::unavailable(e.to_string())
+            } else {
+                e.downcast::<Problem>()
+                    .unwrap_or_else(|e| Problem::new("serverInternal", format!("{e:#}")))
+            };
+            warn!("ACME request failed: {problem}");
+            return Ok(response
+                .status(StatusCode::from_u16(problem.status).context("illegal problem status")?)
+                .insert_header(("Replay-Nonce", nonce))
+                .content_type("application/problem+json")
+                .body(problem.to_json().to_string()));
+        }
+    };
+
+    let mut response = match reply.created {
+        true => HttpResponse::Created(),
+        false => HttpResponse::Ok(),
+    };
+    response
+        .insert_header(("Replay-Nonce", nonce))
+        .insert_header((header::CACHE_CONTROL, "no-store"));
+    if let Some(location) = reply.location {
+        response.insert_header((header::LOCATION, location));
+    }
+
+    Ok(match reply.body {
+        AcmeBody::Json(body) => response.json(body),
+        AcmeBody::PemChain(chain) => response
+            .content_type("application/pem-certificate-chain")
+            .body(chain),
+    })
+}
+
+pub async fn acme_directory(
+    request: HttpRequest,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let directory = aas.acme_directory(&base_url(&request)).await;
+    Ok(HttpResponse::Ok().json(directory))
+}
+
+pub async fn acme_new_nonce(
+    request: HttpRequest,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    limit_source(&aas, &request).await?;
+    let mut response = match request.method() == actix_web::http::Method::HEAD {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::NoContent(),
+    };
+    Ok(response
+        .insert_header(("Replay-Nonce", aas.acme_nonce().await?))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish())
+}
+
+pub async fn acme_new_account(
+    request: HttpRequest,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_new_account(&base_url(&request), jws.0).await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+pub async fn acme_account(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_account(&base_url(&request), &id, jws.0).await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+pub async fn acme_new_order(
+    request: HttpRequest,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_new_order(&base_url(&request), jws.0, &source(&request))
+            .await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+pub async fn acme_order(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_order(&base_url(&request), &id, jws.0).await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+pub async fn acme_authorization(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_authorization(&base_url(&request), &id, jws.0)
+            .await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+pub async fn acme_challenge(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_challenge(&base_url(&request), &id, jws.0, &source(&request))
+            .await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+pub async fn acme_finalize(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_finalize(&base_url(&request), &id, jws.0).await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+pub async fn acme_certificate(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jws: web::Json<Jws>,
+    aas: web::Data<Arc<Server>>,
+) -> Result<HttpResponse> {
+    let reply = async {
+        limit_source(&aas, &request).await?;
+        aas.acme_certificate(&base_url(&request), &id, jws.0).await
+    }
+    .await;
+    acme_response(&aas, reply).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn register(issuance: serde_json::Value) -> Register {
+        let mut register = json!({
+            "id": "spiffe://w",
+            "policy_ids": ["default"],
+            "allowed_resources": [],
+        });
+        register
+            .as_object_mut()
+            .unwrap()
+            .extend(issuance.as_object().unwrap().clone());
+        serde_json::from_value(register).unwrap()
+    }
+
+    #[test]
+    fn registration_needs_admin() {
+        let admin = AdminAuth::new("secret").unwrap();
+        let anonymous = TestRequest::default().to_http_request();
+        let authorized = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        let wrong = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer other"))
+            .to_http_request();
+        let client = register(json!({}));
+        let server = register(json!({ "profile": "server", "dns_names": ["evil.example"] }));
+        let sans = register(json!({ "ip_addresses": ["10.0.0.8"] }));
+
+        // Without `[admin]` only the default client certificates.
+        assert!(authorize_registration(None, &anonymous, &client).is_ok());
+        assert!(authorize_registration(None, &anonymous, &server).is_err());
+        assert!(authorize_registration(None, &anonymous, &sans).is_err());
+        assert!(authorize_registration(None, &authorized, &server).is_err());
+
+        // With `[admin]` only by the admin.
+        for register in [&client, &server, &sans] {
+            assert!(authorize_registration(Some(&admin), &authorized, register).is_ok());
+            assert!(authorize_registration(Some(&admin), &anonymous, register).is_err());
+            assert!(authorize_registration(Some(&admin), &wrong, register).is_err());
+        }
+    }
 }
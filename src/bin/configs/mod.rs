@@ -2,43 +2,200 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::net::SocketAddr;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
+use anyhow::Context;
 use attestation_auth_server::{
-    attestation::{coco_restful::Client as CoCoRestfulClient, AttestationService},
-    ca::{ManualCA, SampleCA, CA},
+    attestation::{
+        coco_restful::{Client as CoCoRestfulClient, ClientOptions, Credentials},
+        routing::Route,
+        AttestationService,
+    },
+    ca::{pkcs11::Pkcs11CA, rotation::KeyState, ManualCA, SampleCA, CA},
+    limit::Limits,
+    store::{local::LocalStore, redis::RedisStore, SessionStore},
+    token::TokenIssuer,
 };
-use config::File;
+use config::{Environment, File};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct Config {
     pub attestation_timeout: i64,
     pub attestation_service: ASConfig,
+    /// Attestation services besides `attestation_service`, by name.
+    #[serde(default)]
+    pub attestation_backends: BTreeMap<String, ASConfig>,
+    /// Which backends verify the evidence of a TEE type. The first matching
+    /// route applies, and `attestation_service` verifies the rest.
+    #[serde(default)]
+    pub attestation_routes: Vec<Route>,
     pub ca: CaConfig,
+    /// CA keys besides `ca`, e.g. the previous CA during a key rotation.
+    #[serde(default)]
+    pub ca_keys: Vec<CaKeyConfig>,
+    /// Lifetime of the issued certificates in seconds.
+    #[serde(default = "default_cert_ttl")]
+    pub cert_ttl: i64,
+    /// Seconds before their expiry from which certificates can be renewed.
+    #[serde(default = "default_renewal_window")]
+    pub renewal_window: i64,
     pub https_private_key: String,
     pub https_cert: String,
     pub client_root_ca_cert: String,
     pub socket: SocketAddr,
+    #[serde(default)]
+    pub session_store: StoreConfig,
+    pub token: Option<TokenConfig>,
+    #[serde(default)]
+    pub ledger: LedgerConfig,
+    /// Revoke a client certificate once it has been renewed via `/rcar/renew`.
+    #[serde(default)]
+    pub revoke_renewed_certs: bool,
+    /// Export traces over OTLP.
+    pub tracing: Option<TracingConfig>,
+    /// Authentication of the `/admin` endpoints, which are not served
+    /// without it.
+    pub admin: Option<AdminConfig>,
+    /// Rate limits and lockout of the RCAR endpoints.
+    #[serde(default)]
+    pub limits: Limits,
+    /// Seconds to wait on SIGTERM for pending handshakes before exiting.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Check the config file for changes every given seconds and reload it.
+    /// It is reloaded on SIGHUP in any case.
+    pub config_watch_interval: Option<u64>,
+    /// Files read for `file:` values, watched along with the config file.
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
 }
 
 impl TryFrom<&str> for Config {
     type Error = anyhow::Error;
 
-    /// Load `Config` from a configuration file.
+    /// Load `Config` from a configuration file. Every key can be overridden
+    /// by an `AAS_` environment variable, with `__` between nested keys, e.g.
+    /// `AAS_SESSION_STORE__REDIS__URL`.
     fn try_from(config_path: &str) -> Result<Self, Self::Error> {
         let c = config::Config::builder()
             .add_source(File::with_name(config_path))
+            .add_source(
+                Environment::with_prefix("AAS")
+                    .prefix_separator("_")
+                    .separator("__")
+                    .try_parsing(true),
+            )
             .build()?;
 
-        c.try_deserialize()
-            .map_err(|e| anyhow::anyhow!("invalid config: {}", e.to_string()))
+        let mut config: Self = c
+            .try_deserialize()
+            .map_err(|e| anyhow::anyhow!("invalid config: {e}"))?;
+        config.resolve_values()?;
+        config.limits.check()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Resolve the `file:` and `env:` values of the keys and certificates.
+    fn resolve_values(&mut self) -> anyhow::Result<()> {
+        let mut resolver = Resolver::default();
+        resolver.resolve(&mut self.https_private_key)?;
+        resolver.resolve(&mut self.https_cert)?;
+        resolver.resolve(&mut self.client_root_ca_cert)?;
+        self.ca.resolve_values(&mut resolver)?;
+        self.attestation_service.resolve_values(&mut resolver)?;
+        for backend in self.attestation_backends.values_mut() {
+            backend.resolve_values(&mut resolver)?;
+        }
+        for key in &mut self.ca_keys {
+            key.ca.resolve_values(&mut resolver)?;
+        }
+        if let Some(private_key) = self.token.as_mut().and_then(|t| t.private_key.as_mut()) {
+            resolver.resolve(private_key)?;
+        }
+        if let Some(admin) = &mut self.admin {
+            resolver.resolve(&mut admin.token)?;
+        }
+
+        self.files = resolver.files;
+        Ok(())
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_cert_ttl() -> i64 {
+    24 * 3600
+}
+
+fn default_renewal_window() -> i64 {
+    8 * 3600
+}
+
+/// Replaces `file:<path>` values by the content of the file and
+/// `env:<variable>` values by the environment variable. Other values are
+/// taken inline.
+#[derive(Default)]
+struct Resolver {
+    files: Vec<PathBuf>,
+}
+
+impl Resolver {
+    fn resolve(&mut self, value: &mut String) -> anyhow::Result<()> {
+        if let Some(path) = value.strip_prefix("file:") {
+            let path = PathBuf::from(path);
+            *value =
+                fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+            self.files.push(path);
+        } else if let Some(variable) = value.strip_prefix("env:") {
+            *value = env::var(variable).with_context(|| format!("read env {variable}"))?;
+        }
+
+        Ok(())
     }
 }
 
 #[derive(Deserialize)]
 pub enum ASConfig {
-    RestfulCoCo { addr: String },
+    RestfulCoCo {
+        addr: String,
+        #[serde(flatten)]
+        options: ClientOptions,
+        #[serde(flatten)]
+        credentials: Credentials,
+    },
+}
+
+impl ASConfig {
+    fn resolve_values(&mut self, resolver: &mut Resolver) -> anyhow::Result<()> {
+        match self {
+            ASConfig::RestfulCoCo { credentials, .. } => {
+                let Credentials {
+                    ca_cert,
+                    client_cert,
+                    client_key,
+                    bearer_token,
+                } = credentials;
+                for value in [ca_cert, client_cert, client_key, bearer_token]
+                    .into_iter()
+                    .flatten()
+                {
+                    resolver.resolve(value)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl TryInto<AttestationService> for ASConfig {
@@ -46,20 +203,62 @@ impl TryInto<AttestationService> for ASConfig {
 
     fn try_into(self) -> Result<AttestationService, Self::Error> {
         match self {
-            ASConfig::RestfulCoCo { addr } => Ok(AttestationService::CoCoRestful(
-                CoCoRestfulClient::new(addr),
+            ASConfig::RestfulCoCo {
+                addr,
+                options,
+                credentials,
+            } => Ok(AttestationService::CoCoRestful(
+                CoCoRestfulClient::with_options(addr, options, &credentials)?,
             )),
         }
     }
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CaConfig {
-    Sample,
+    Sample {
+        /// Directory to keep the generated root key and certificate across
+        /// restarts. A new root is generated on every start if not given.
+        persist_dir: Option<String>,
+    },
     Manual {
         private_key: String,
         public_key_cert: String,
     },
+    Pkcs11 {
+        /// Path to the PKCS#11 module, e.g. `/usr/lib/softhsm/libsofthsm2.so`.
+        module: String,
+        slot: u64,
+        key_label: String,
+        pin: String,
+        public_key_cert: String,
+    },
+}
+
+impl CaConfig {
+    fn resolve_values(&mut self, resolver: &mut Resolver) -> anyhow::Result<()> {
+        match self {
+            CaConfig::Sample { .. } => {}
+            CaConfig::Manual {
+                private_key,
+                public_key_cert,
+            } => {
+                resolver.resolve(private_key)?;
+                resolver.resolve(public_key_cert)?;
+            }
+            CaConfig::Pkcs11 {
+                pin,
+                public_key_cert,
+                ..
+            } => {
+                resolver.resolve(pin)?;
+                resolver.resolve(public_key_cert)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl TryInto<CA> for CaConfig {
@@ -67,11 +266,148 @@ impl TryInto<CA> for CaConfig {
 
     fn try_into(self) -> Result<CA, Self::Error> {
         match self {
-            CaConfig::Sample {} => Ok(CA::Sample(SampleCA {})),
+            CaConfig::Sample { persist_dir } => Ok(CA::Sample(SampleCA::new(
+                persist_dir.as_deref().map(Path::new),
+            )?)),
             CaConfig::Manual {
                 private_key,
                 public_key_cert,
             } => Ok(CA::Manual(ManualCA::new(private_key, public_key_cert)?)),
+            CaConfig::Pkcs11 {
+                module,
+                slot,
+                key_label,
+                pin,
+                public_key_cert,
+            } => Ok(CA::Pkcs11(Pkcs11CA::new(
+                &module,
+                slot,
+                &key_label,
+                pin,
+                public_key_cert,
+            )?)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub enum StoreConfig {
+    Local {
+        /// File to keep the sessions and the ledger in across restarts.
+        snapshot_path: Option<String>,
+    },
+    Redis {
+        url: String,
+        #[serde(default = "default_redis_prefix")]
+        prefix: String,
+    },
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self::Local {
+            snapshot_path: None,
+        }
+    }
+}
+
+fn default_redis_prefix() -> String {
+    "aas".into()
+}
+
+#[derive(Deserialize)]
+pub struct CaKeyConfig {
+    pub id: String,
+    /// Either `verifyonly` or `retired`. `ca` is the only active key.
+    pub state: KeyState,
+    pub ca: CaConfig,
+}
+
+#[derive(Deserialize, Default)]
+pub struct LedgerConfig {
+    /// Chain the ledger entries by their hashes to detect tampering.
+    #[serde(default)]
+    pub hash_chain: bool,
+}
+
+/// Authentication of the `/admin` endpoints.
+#[derive(Deserialize)]
+pub struct AdminConfig {
+    /// Expected as `Authorization: Bearer <token>` on every admin request.
+    pub token: String,
+}
+
+/// Issue signed access tokens along with the certificates.
+#[derive(Deserialize)]
+pub struct TokenConfig {
+    /// Expected `aud` of the tokens.
+    pub audience: String,
+    #[serde(default = "default_token_issuer")]
+    pub issuer: String,
+    /// Token lifetime in seconds.
+    #[serde(default = "default_token_ttl")]
+    pub ttl: i64,
+    /// P-256 PKCS#8 PEM key to sign the tokens. The active CA key is used if
+    /// not given.
+    pub private_key: Option<String>,
+}
+
+fn default_token_issuer() -> String {
+    "attestation-auth-server".into()
+}
+
+fn default_token_ttl() -> i64 {
+    300
+}
+
+impl TokenConfig {
+    pub fn into_issuer(self) -> anyhow::Result<TokenIssuer> {
+        TokenIssuer::new(
+            self.private_key.as_deref(),
+            self.issuer,
+            self.audience,
+            self.ttl,
+        )
+    }
+}
+
+/// OTLP/HTTP exporter of the traces.
+#[derive(Deserialize)]
+pub struct TracingConfig {
+    /// Traces endpoint of the collector, e.g. `http://collector:4318/v1/traces`.
+    pub otlp_endpoint: String,
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    #[default]
+    Protobuf,
+    Json,
+}
+
+fn default_service_name() -> String {
+    "aas".into()
+}
+
+impl TryInto<SessionStore> for StoreConfig {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<SessionStore, Self::Error> {
+        match self {
+            StoreConfig::Local {
+                snapshot_path: None,
+            } => Ok(SessionStore::default()),
+            StoreConfig::Local {
+                snapshot_path: Some(path),
+            } => Ok(SessionStore::Local(LocalStore::open(Path::new(&path))?)),
+            StoreConfig::Redis { url, prefix } => {
+                Ok(SessionStore::Redis(RedisStore::new(&url, prefix)?))
+            }
         }
     }
 }
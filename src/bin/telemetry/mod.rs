@@ -0,0 +1,74 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashMap, io::IsTerminal};
+
+use actix_web::HttpRequest;
+use anyhow::Result;
+use attestation_auth_server::telemetry;
+use opentelemetry::{global, trace::TracerProvider as _};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::{info_span, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::configs::{OtlpProtocol, TracingConfig};
+
+/// Log to stderr, filtered by `RUST_LOG` (`info` by default), and export the
+/// spans over OTLP if `config` is given. The returned provider must be shut
+/// down to flush the last spans.
+pub fn init(config: Option<&TracingConfig>) -> Result<Option<SdkTracerProvider>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry().with(filter).with(
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal()),
+    );
+
+    let Some(config) = config else {
+        registry.try_init()?;
+        return Ok(None);
+    };
+
+    let protocol = match config.protocol {
+        OtlpProtocol::Protobuf => Protocol::HttpBinary,
+        OtlpProtocol::Json => Protocol::HttpJson,
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_protocol(protocol)
+        .with_endpoint(&config.otlp_endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    registry
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("aas")))
+        .try_init()?;
+    Ok(Some(provider))
+}
+
+/// Span serving `request`, continuing the trace of its `traceparent` header.
+pub fn request_span(request: &HttpRequest) -> Span {
+    let span = info_span!(
+        "http.request",
+        method = %request.method(),
+        path = request.path(),
+    );
+
+    let fields: HashMap<String, String> = request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    telemetry::set_parent(&span, &fields);
+    span
+}
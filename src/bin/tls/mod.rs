@@ -0,0 +1,210 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTPS material that can be swapped while the server is running.
+
+use std::{
+    io::Cursor,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context, Result};
+use rustls::{
+    client::HandshakeSignatureValid,
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, ClientCertVerified, ClientCertVerifier,
+        ClientHello, ResolvesServerCert,
+    },
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, DigitallySignedStruct, DistinguishedName, PrivateKey, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use rustls_pemfile::pkcs8_private_keys;
+
+use crate::configs::Config;
+
+/// The HTTPS certificate and the client root CA of a [`Config`], parsed.
+pub struct TlsMaterial {
+    cert: Arc<CertifiedKey>,
+    /// DER of the client root CA certificates.
+    client_roots: Vec<Vec<u8>>,
+}
+
+impl TlsMaterial {
+    pub fn load(config: &Config) -> Result<Self> {
+        // HTTPS public key cert
+        let mut cursor = Cursor::new(&config.https_cert);
+        let https_cert_chain = rustls_pemfile::certs(&mut cursor)?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+
+        // HTTPS private key
+        let mut cursor = Cursor::new(&config.https_private_key);
+        let https_key = pkcs8_private_keys(&mut cursor)?
+            .into_iter()
+            .next()
+            .map(PrivateKey)
+            .ok_or_else(|| anyhow!("no PKCS#8 HTTPS private key"))?;
+        let https_key = any_supported_type(&https_key).context("illegal HTTPS private key")?;
+
+        // mTLS client key root cert
+        let mut cursor = Cursor::new(&config.client_root_ca_cert);
+        let client_roots = rustls_pemfile::certs(&mut cursor)?;
+
+        Ok(Self {
+            cert: Arc::new(CertifiedKey::new(https_cert_chain, https_key)),
+            client_roots,
+        })
+    }
+}
+
+/// Client certificates signed by one of `roots`, given as DER, are verified.
+fn client_verifier(roots: &[Vec<u8>]) -> (Arc<dyn ClientCertVerifier>, Vec<DistinguishedName>) {
+    let mut client_root_cert_store = RootCertStore::empty();
+    let (_, _skip) = client_root_cert_store.add_parsable_certificates(roots);
+    let client_subjects = client_root_cert_store
+        .roots
+        .iter()
+        .map(|root| root.subject().clone())
+        .collect();
+    (
+        AllowAnyAnonymousOrAuthenticatedClient::new(client_root_cert_store).boxed(),
+        client_subjects,
+    )
+}
+
+/// DER of the certificates trusted to issue client certificates.
+struct ClientRoots {
+    /// The client root CA of the configuration.
+    configured: Vec<Vec<u8>>,
+    /// The published CA keys.
+    ca_keys: Vec<Vec<u8>>,
+}
+
+/// Resolves the HTTPS certificate and verifies mTLS clients with the
+/// material loaded last. Clients are also trusted if their certificate was
+/// issued by one of the published CA keys, which change with their rotation.
+pub struct ReloadableTls {
+    cert: RwLock<Arc<CertifiedKey>>,
+    client_roots: RwLock<ClientRoots>,
+    client_verifier: RwLock<Arc<dyn ClientCertVerifier>>,
+    /// rustls borrows the subjects from the verifier, so those of every
+    /// loaded client root CA are leaked. Reloads are rare enough for this
+    /// not to matter.
+    client_subjects: RwLock<&'static [DistinguishedName]>,
+}
+
+impl ReloadableTls {
+    pub fn new(material: TlsMaterial) -> Arc<Self> {
+        let (client_verifier, client_subjects) = client_verifier(&material.client_roots);
+        Arc::new(Self {
+            cert: RwLock::new(material.cert),
+            client_roots: RwLock::new(ClientRoots {
+                configured: material.client_roots,
+                ca_keys: Vec::new(),
+            }),
+            client_verifier: RwLock::new(client_verifier),
+            client_subjects: RwLock::new(client_subjects.leak()),
+        })
+    }
+
+    pub fn swap(&self, material: TlsMaterial) {
+        *self.cert.write().expect("poisoned") = material.cert;
+        let mut client_roots = self.client_roots.write().expect("poisoned");
+        client_roots.configured = material.client_roots;
+        self.swap_client_verifier(&client_roots);
+    }
+
+    /// Trust the client certificates issued by `ca_certs`, the DER of the
+    /// published CA keys, instead of those published before.
+    pub fn trust_ca(&self, ca_certs: Vec<Vec<u8>>) {
+        let mut client_roots = self.client_roots.write().expect("poisoned");
+        client_roots.ca_keys = ca_certs;
+        self.swap_client_verifier(&client_roots);
+    }
+
+    fn swap_client_verifier(&self, client_roots: &ClientRoots) {
+        let roots: Vec<_> = client_roots
+            .configured
+            .iter()
+            .chain(&client_roots.ca_keys)
+            .cloned()
+            .collect();
+        let (client_verifier, client_subjects) = client_verifier(&roots);
+        *self.client_verifier.write().expect("poisoned") = client_verifier;
+        *self.client_subjects.write().expect("poisoned") = client_subjects.leak();
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(Arc::new(ReloadableClientVerifier(self.clone())))
+            .with_cert_resolver(self.clone())
+    }
+
+    fn client_verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        self.client_verifier.read().expect("poisoned").clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadableTls {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.cert.read().expect("poisoned").clone())
+    }
+}
+
+struct ReloadableClientVerifier(Arc<ReloadableTls>);
+
+impl ClientCertVerifier for ReloadableClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.0.client_verifier().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.0.client_verifier().client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        *self.0.client_subjects.read().expect("poisoned")
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.0
+            .client_verifier()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0
+            .client_verifier()
+            .verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0
+            .client_verifier()
+            .verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.client_verifier().supported_verify_schemes()
+    }
+}
@@ -2,23 +2,64 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
-use scc::HashMap;
+use std::sync::Arc;
 
-use crate::{attestation::AttestationService, ca::CA, server::Server};
+use anyhow::{bail, Result};
+use chrono::{Duration, Utc};
+use tokio::sync::RwLock;
+
+use crate::{
+    attestation::{
+        routing::{Backends, Route},
+        AttestationService,
+    },
+    ca::{
+        rotation::{KeyRing, KeyState},
+        CA,
+    },
+    ledger::Ledger,
+    limit::{Limiter, Limits},
+    metrics::Metrics,
+    server::Server,
+    store::SessionStore,
+    token::TokenIssuer,
+};
+
+/// Id of the CA key given by [`ServerBuilder::with_ca`].
+pub const DEFAULT_CA_KEY_ID: &str = "default";
 
 pub struct ServerBuilder {
     ca: Option<CA>,
+    extra_ca_keys: Vec<(String, KeyState, CA)>,
     attestation_service: Option<AttestationService>,
+    attestation_backends: Vec<(String, AttestationService)>,
+    attestation_routes: Vec<Route>,
     attestation_timeout: i64,
+    cert_ttl: i64,
+    renewal_window: i64,
+    store: Option<SessionStore>,
+    token_issuer: Option<TokenIssuer>,
+    ledger: Ledger,
+    revoke_renewed: bool,
+    limits: Limits,
 }
 
 impl Default for ServerBuilder {
     fn default() -> Self {
         Self {
             ca: None,
+            extra_ca_keys: Vec::new(),
             attestation_service: None,
+            attestation_backends: Vec::new(),
+            attestation_routes: Vec::new(),
             attestation_timeout: 600,
+            cert_ttl: 24 * 3600,
+            renewal_window: 8 * 3600,
+            store: None,
+            token_issuer: None,
+            ledger: Ledger::default(),
+            revoke_renewed: false,
+            limits: Limits::default(),
         }
     }
 }
@@ -33,22 +74,176 @@ impl ServerBuilder {
         self
     }
 
+    /// Add a CA key besides the active one given by [`ServerBuilder::with_ca`],
+    /// e.g. to keep publishing the previous CA during a rotation.
+    pub fn with_ca_key(mut self, id: String, state: KeyState, ca: CA) -> Self {
+        self.extra_ca_keys.push((id, state, ca));
+        self
+    }
+
     pub fn with_attestation_service(mut self, attestation_service: AttestationService) -> Self {
         self.attestation_service = Some(attestation_service);
         self
     }
 
+    /// Add an attestation service besides the default one given by
+    /// [`ServerBuilder::with_attestation_service`], to be routed to by
+    /// [`ServerBuilder::with_attestation_route`].
+    pub fn with_attestation_backend(mut self, name: String, backend: AttestationService) -> Self {
+        self.attestation_backends.push((name, backend));
+        self
+    }
+
+    /// Verify the evidence matching `route` with its backends instead of the
+    /// default one. The first matching route applies.
+    pub fn with_attestation_route(mut self, route: Route) -> Self {
+        self.attestation_routes.push(route);
+        self
+    }
+
     pub fn with_attestation_timeout(mut self, timeout: i64) -> Self {
         self.attestation_timeout = timeout;
         self
     }
 
+    /// Lifetime of the issued certificates in seconds, 24 hours by default.
+    /// It must be positive and must not outlive the active CA certificate.
+    pub fn with_cert_ttl(mut self, ttl: i64) -> Self {
+        self.cert_ttl = ttl;
+        self
+    }
+
+    /// Seconds before their expiry from which certificates can be renewed,
+    /// 8 hours by default. It must be shorter than the certificate lifetime.
+    pub fn with_renewal_window(mut self, window: i64) -> Self {
+        self.renewal_window = window;
+        self
+    }
+
+    pub fn with_session_store(mut self, store: SessionStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn with_token_issuer(mut self, token_issuer: TokenIssuer) -> Self {
+        self.token_issuer = Some(token_issuer);
+        self
+    }
+
+    pub fn with_ledger(mut self, ledger: Ledger) -> Self {
+        self.ledger = ledger;
+        self
+    }
+
+    /// Revoke a certificate once it has been renewed.
+    pub fn with_revoke_renewed(mut self, revoke: bool) -> Self {
+        self.revoke_renewed = revoke;
+        self
+    }
+
+    /// Rate limits and lockout of the RCAR endpoints, all off by default.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     pub fn build(self) -> Result<Server> {
+        let ca = KeyRing::with_keys(self.ca.expect("must initialized"), self.extra_ca_keys)?;
+        if self.cert_ttl <= 0 {
+            bail!("cert_ttl must be positive, got {}", self.cert_ttl);
+        }
+        if self.renewal_window < 0 || self.renewal_window >= self.cert_ttl {
+            bail!(
+                "renewal_window must be between 0 and cert_ttl ({}), got {}",
+                self.cert_ttl,
+                self.renewal_window
+            );
+        }
+        let ca_not_after = ca.active().not_after()?;
+        let not_after =
+            Duration::try_seconds(self.cert_ttl).and_then(|ttl| Utc::now().checked_add_signed(ttl));
+        if not_after.is_none_or(|not_after| not_after > ca_not_after) {
+            bail!(
+                "cert_ttl of {} seconds outlives the CA certificate, which expires at {ca_not_after}",
+                self.cert_ttl
+            );
+        }
+
+        self.limits.check()?;
+
+        let attestation_service = Backends::new(
+            self.attestation_service.expect("must be initialized"),
+            self.attestation_backends,
+            self.attestation_routes,
+        )?;
+
+        if let Some(issuer) = &self.token_issuer {
+            issuer.signing_key(&ca)?;
+        }
+
         Ok(Server {
-            ca: self.ca.expect("must initialized"),
-            registered_identities: HashMap::new(),
-            attestation_service: self.attestation_service.expect("must be initialized"),
+            ca: RwLock::new(ca),
+            store: self.store.unwrap_or_default(),
+            token_issuer: self.token_issuer,
+            ledger: self.ledger,
+            attestation_service: RwLock::new(Arc::new(attestation_service)),
             attestation_timeout: self.attestation_timeout,
+            cert_ttl: self.cert_ttl,
+            renewal_window: self.renewal_window,
+            revoke_renewed: self.revoke_renewed,
+            metrics: Metrics::new()?,
+            draining: Default::default(),
+            limiter: Limiter::new(self.limits),
+            readiness: Default::default(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{attestation::Mock, ca::SampleCA};
+
+    fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+            .with_ca(CA::Sample(SampleCA::new(None).unwrap()))
+            .with_attestation_service(AttestationService::Mock(Mock::Accept("default")))
+    }
+
+    #[test]
+    fn cert_ttl() {
+        assert!(builder().build().is_ok());
+        assert!(builder().with_cert_ttl(30 * 24 * 3600).build().is_ok());
+
+        for ttl in [0, -1, i64::MIN] {
+            let err = builder().with_cert_ttl(ttl).build().err().unwrap();
+            assert!(err.to_string().contains("must be positive"), "{err}");
+        }
+
+        // The sample CA is valid for a year.
+        for ttl in [400 * 24 * 3600, i64::MAX] {
+            let err = builder().with_cert_ttl(ttl).build().err().unwrap();
+            assert!(err.to_string().contains("outlives the CA"), "{err}");
+        }
+    }
+
+    #[test]
+    fn renewal_window() {
+        assert!(builder().with_renewal_window(0).build().is_ok());
+        assert!(builder()
+            .with_cert_ttl(3600)
+            .with_renewal_window(3599)
+            .build()
+            .is_ok());
+
+        for (ttl, window) in [(3600, 3600), (3600, 7200), (3600, -1)] {
+            let err = builder()
+                .with_cert_ttl(ttl)
+                .with_renewal_window(window)
+                .build()
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("renewal_window"), "{err}");
+        }
+    }
+}
@@ -2,74 +2,356 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod pkcs11;
+pub mod profile;
+pub mod rotation;
+
+use std::{
+    fs::{self, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
+
 use anyhow::*;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use log::info;
+use rand::{thread_rng, Rng};
 use rcgen::{
-    Certificate, CertificateParams, CertificateSigningRequest, DnType, ExtendedKeyUsagePurpose,
-    KeyPair, KeyUsagePurpose,
+    BasicConstraints, Certificate, CertificateParams, CertificateSigningRequest, DistinguishedName,
+    DnType, IsCa, KeyPair, KeyUsagePurpose, SerialNumber, PKCS_ECDSA_P256_SHA256,
 };
+use serde::{Deserialize, Serialize};
+use time::{Duration as TimeDuration, OffsetDateTime};
+use x509_parser::pem::Pem;
+
+use self::profile::IssuancePolicy;
+use crate::ratls::AttestationClaims;
+
+/// A certificate issued by a [`CA`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IssuedCert {
+    /// PEM of the issued certificate.
+    pub crt: String,
+    /// PEM of the issuing CA certificate followed by its intermediate
+    /// issuers. The self-signed root is not included.
+    pub chain: Vec<String>,
+    pub not_after: DateTime<Utc>,
+    /// Hex encoded serial number of `crt`.
+    pub serial: String,
+}
+
+impl IssuedCert {
+    fn new(der: Vec<u8>, chain: Vec<String>) -> Result<Self> {
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(&der).context("parse issued certificate")?;
+        let not_after = DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+            .context("illegal notAfter of issued certificate")?;
+        let serial = serial_hex(cert.raw_serial());
+
+        Ok(Self {
+            crt: der_to_pem("CERTIFICATE", &der),
+            chain,
+            not_after,
+            serial,
+        })
+    }
+}
+
+/// Hex encoding of a raw certificate serial number, as in [`IssuedCert::serial`].
+pub fn serial_hex(raw_serial: &[u8]) -> String {
+    raw_serial.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn der_to_pem(label: &str, der: &[u8]) -> String {
+    let base64 = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ascii"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+fn pem_to_der(bundle: &str) -> Result<Vec<Vec<u8>>> {
+    Pem::iter_from_buffer(bundle.as_bytes())
+        .map(|pem| Ok(pem.context("illegal CA certificate PEM")?.contents))
+        .collect()
+}
+
+/// A random positive serial number, so that re-issuing for the same key does
+/// not reuse a serial.
+fn random_serial() -> SerialNumber {
+    let mut serial = [0u8; 16];
+    thread_rng().fill(&mut serial[..]);
+    serial[0] &= 0x7f;
+    SerialNumber::from_slice(&serial)
+}
+
+/// PEM of the certificates in `bundle` except the self-signed ones.
+fn intermediate_chain(bundle: &str) -> Result<Vec<String>> {
+    let mut chain = Vec::new();
+    for pem in Pem::iter_from_buffer(bundle.as_bytes()) {
+        let der = pem.context("illegal CA certificate PEM")?.contents;
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(&der).context("parse CA certificate")?;
+        if cert.subject() != cert.issuer() {
+            chain.push(der_to_pem("CERTIFICATE", &der));
+        }
+    }
+
+    Ok(chain)
+}
 
 pub enum CA {
     Sample(SampleCA),
     Manual(ManualCA),
+    Pkcs11(pkcs11::Pkcs11CA),
 }
 
 impl CA {
-    pub async fn issue_cert(&self, csr: &str) -> Result<String> {
+    /// Issue a certificate for `csr` of `id` as permitted by `policy`,
+    /// carrying the attestation `claims` of its holder and valid for `ttl`
+    /// seconds.
+    pub async fn issue_cert(
+        &self,
+        id: &str,
+        csr: &str,
+        policy: &IssuancePolicy,
+        claims: &AttestationClaims,
+        ttl: i64,
+    ) -> Result<IssuedCert> {
+        match self {
+            CA::Sample(inner) => inner.issue_cert(id, csr, policy, claims, ttl).await,
+            CA::Manual(inner) => inner.issue_cert(id, csr, policy, claims, ttl).await,
+            CA::Pkcs11(inner) => inner.issue_cert(id, csr, policy, claims, ttl).await,
+        }
+    }
+
+    /// PKCS#8 PEM of the CA signing key, used to sign access tokens when no
+    /// dedicated token key is configured.
+    pub fn signing_key_pem(&self) -> Result<String> {
+        match self {
+            CA::Sample(inner) => Ok(inner.ca.serialize_private_key_pem()),
+            CA::Manual(inner) => Ok(inner.ca.serialize_private_key_pem()),
+            CA::Pkcs11(_) => bail!("PKCS#11 CA signing key is not extractable"),
+        }
+    }
+
+    /// PEM encoded CA certificates that relying parties need to verify the
+    /// issued certificates.
+    pub fn trust_bundle(&self) -> Result<String> {
         match self {
-            CA::Sample(inner) => inner.issue_cert(csr).await,
-            CA::Manual(inner) => inner.issue_cert(csr).await,
+            CA::Sample(inner) => Ok(inner.public_key_cert.clone()),
+            CA::Manual(inner) => Ok(inner.public_key_cert.clone()),
+            CA::Pkcs11(inner) => Ok(inner.trust_bundle()),
         }
     }
+
+    /// DER encoded certificates of [`CA::trust_bundle`].
+    pub fn trust_bundle_der(&self) -> Result<Vec<Vec<u8>>> {
+        pem_to_der(&self.trust_bundle()?)
+    }
+
+    /// Expiry of the CA certificate, past which the certificates it issues
+    /// no longer verify.
+    pub fn not_after(&self) -> Result<DateTime<Utc>> {
+        let der = self
+            .trust_bundle_der()?
+            .into_iter()
+            .next()
+            .context("empty CA trust bundle")?;
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(&der).context("parse CA certificate")?;
+        DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+            .context("illegal notAfter of CA certificate")
+    }
 }
 
-#[derive(Debug)]
-pub struct SampleCA {}
+/// Tolerated clock skew of the relying parties, by which the issued
+/// certificates are backdated.
+const BACKDATE_SECS: i64 = 300;
+
+/// Sign the public key of `csr` of `id` with `ca` for `ttl` seconds, as
+/// permitted by `policy`.
+fn sign_csr(
+    id: &str,
+    csr: &str,
+    ca: &Certificate,
+    policy: &IssuancePolicy,
+    claims: &AttestationClaims,
+    ttl: i64,
+) -> Result<Vec<u8>> {
+    let mut csr_pem = CertificateSigningRequest::from_pem(csr)?;
+    csr_pem.params.serial_number = Some(random_serial());
+    let now = OffsetDateTime::now_utc();
+    csr_pem.params.not_before = now - TimeDuration::seconds(BACKDATE_SECS);
+    csr_pem.params.not_after = now + TimeDuration::seconds(ttl);
+    csr_pem
+        .params
+        .key_usages
+        .push(KeyUsagePurpose::DigitalSignature);
+    csr_pem
+        .params
+        .key_usages
+        .push(KeyUsagePurpose::KeyEncipherment);
+    policy.apply(id, &mut csr_pem.params)?;
+    csr_pem
+        .params
+        .custom_extensions
+        .push(claims.to_extension()?);
+    csr_pem.params.distinguished_name.remove(DnType::CommonName);
+    csr_pem
+        .params
+        .distinguished_name
+        .push(DnType::CommonName, "NiuBi Certificate");
+
+    Ok(csr_pem.serialize_der_with_signer(ca)?)
+}
+
+/// A CA for development. It signs with a self-signed root generated at
+/// startup, which is optionally persisted so that it survives restarts.
+pub struct SampleCA {
+    ca: Box<Certificate>,
+    public_key_cert: String,
+}
 
 impl SampleCA {
-    async fn issue_cert(&self, csr: &str) -> Result<String> {
-        let csr_pem = CertificateSigningRequest::from_pem(csr)?;
-        let cert = Certificate::from_params(csr_pem.params)?;
-        let pem = cert.serialize_pem()?;
-        Ok(pem)
+    const KEY_FILE: &'static str = "sample-ca.key";
+    const CERT_FILE: &'static str = "sample-ca.crt";
+
+    /// Load the root from `persist_dir` if it has one, or generate a new root
+    /// and save it there.
+    pub fn new(persist_dir: Option<&Path>) -> Result<Self> {
+        if let Some(dir) = persist_dir {
+            let key_path = dir.join(Self::KEY_FILE);
+            let cert_path = dir.join(Self::CERT_FILE);
+            if key_path.exists() && cert_path.exists() {
+                info!("load sample CA from {}", dir.display());
+                let key_pair = KeyPair::from_pem(&fs::read_to_string(key_path)?)?;
+                let public_key_cert = fs::read_to_string(cert_path)?;
+                let ca = CertificateParams::from_ca_cert_pem(&public_key_cert, key_pair)?;
+                return Ok(Self {
+                    ca: Box::new(Certificate::from_params(ca)?),
+                    public_key_cert,
+                });
+            }
+        }
+
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "AAS Sample Root CA");
+        params.serial_number = Some(random_serial());
+        params.not_before = OffsetDateTime::now_utc() - TimeDuration::hours(1);
+        params.not_after = OffsetDateTime::now_utc() + TimeDuration::days(365);
+
+        let ca = Certificate::from_params(params)?;
+        let public_key_cert = ca.serialize_pem()?;
+        if let Some(dir) = persist_dir {
+            info!("persist sample CA to {}", dir.display());
+            fs::create_dir_all(dir)?;
+            // Only readable by the owner from the start, unlike `fs::write`.
+            // The mode only applies to a new file, so an existing one is
+            // restricted before the key is written to it.
+            let mut key_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(dir.join(Self::KEY_FILE))?;
+            key_file.set_permissions(Permissions::from_mode(0o600))?;
+            key_file.write_all(ca.serialize_private_key_pem().as_bytes())?;
+            fs::write(dir.join(Self::CERT_FILE), &public_key_cert)?;
+        }
+
+        Ok(Self {
+            ca: Box::new(ca),
+            public_key_cert,
+        })
+    }
+
+    async fn issue_cert(
+        &self,
+        id: &str,
+        csr: &str,
+        policy: &IssuancePolicy,
+        claims: &AttestationClaims,
+        ttl: i64,
+    ) -> Result<IssuedCert> {
+        let cert = sign_csr(id, csr, &self.ca, policy, claims, ttl)?;
+        IssuedCert::new(cert, Vec::new())
     }
 }
 
 pub struct ManualCA {
-    ca: Certificate,
+    ca: Box<Certificate>,
+    public_key_cert: String,
+    chain: Vec<String>,
 }
 
 impl ManualCA {
+    /// `public_key_cert` is the PEM of the CA certificate. If the CA is an
+    /// intermediate, its issuers up to the root can be appended.
     pub fn new(private_key: String, public_key_cert: String) -> Result<Self> {
         let key_pair = KeyPair::from_pem(&private_key)?;
+        Self::with_key_pair(key_pair, public_key_cert)
+    }
 
+    fn with_key_pair(key_pair: KeyPair, public_key_cert: String) -> Result<Self> {
         let ca = CertificateParams::from_ca_cert_pem(&public_key_cert, key_pair)?;
-        let ca = Certificate::from_params(ca)?;
-        Ok(Self { ca })
+        let ca = Box::new(Certificate::from_params(ca)?);
+        let chain = intermediate_chain(&public_key_cert)?;
+        Ok(Self {
+            ca,
+            public_key_cert,
+            chain,
+        })
     }
 
-    async fn issue_cert(&self, csr: &str) -> Result<String> {
-        let mut csr_pem = CertificateSigningRequest::from_pem(csr)?;
-        csr_pem.params.key_usages.push(KeyUsagePurpose::KeyCertSign);
-        csr_pem
-            .params
-            .key_usages
-            .push(KeyUsagePurpose::DigitalSignature);
-        csr_pem
-            .params
-            .key_usages
-            .push(KeyUsagePurpose::KeyEncipherment);
-        csr_pem
-            .params
-            .extended_key_usages
-            .push(ExtendedKeyUsagePurpose::ClientAuth);
-        csr_pem.params.distinguished_name.remove(DnType::CommonName);
-        csr_pem
-            .params
-            .distinguished_name
-            .push(DnType::CommonName, "NiuBi Certificate");
+    async fn issue_cert(
+        &self,
+        id: &str,
+        csr: &str,
+        policy: &IssuancePolicy,
+        claims: &AttestationClaims,
+        ttl: i64,
+    ) -> Result<IssuedCert> {
+        let cert = sign_csr(id, csr, &self.ca, policy, claims, ttl)?;
+        IssuedCert::new(cert, self.chain.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_ca_key_permissions() {
+        let dir = std::env::temp_dir().join(format!("aas-sample-ca-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key_path = dir.join(SampleCA::KEY_FILE);
+        // A stale key left behind without its certificate.
+        fs::write(&key_path, "").unwrap();
+        fs::set_permissions(&key_path, Permissions::from_mode(0o644)).unwrap();
+
+        let ca = SampleCA::new(Some(&dir)).unwrap();
+        let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let loaded = SampleCA::new(Some(&dir)).unwrap();
+        assert_eq!(loaded.public_key_cert, ca.public_key_cert);
 
-        let cert = csr_pem.serialize_pem_with_signer(&self.ca)?;
-        // let res = format!("{pem}\n{}", self.public_key_cert);
-        Ok(cert)
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,278 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! CA whose signing key lives in a PKCS#11 token, e.g. an HSM or SoftHSM
+//! for local testing. Only P-256 ECDSA keys are supported.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    error::{Error, RvError},
+    mechanism::Mechanism,
+    object::{Attribute, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    slot::Slot,
+    types::AuthPin,
+};
+use log::info;
+use p256::ecdsa::Signature;
+use rcgen::{KeyPair, RemoteKeyPair, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256};
+use sha2::{Digest, Sha256};
+use x509_parser::pem::Pem;
+
+use super::{profile::IssuancePolicy, IssuedCert, ManualCA};
+use crate::ratls::AttestationClaims;
+
+/// Initialized PKCS#11 modules by path. A module can only be initialized once
+/// per process, so CA keys in the same module, and the CA reloaded on
+/// SIGHUP, share its context.
+static CONTEXTS: LazyLock<Mutex<HashMap<String, Pkcs11>>> = LazyLock::new(Default::default);
+
+/// The context of the PKCS#11 `module`, initialized on first use.
+fn context(module: &str) -> Result<Pkcs11> {
+    let mut contexts = CONTEXTS
+        .lock()
+        .map_err(|_| anyhow!("PKCS#11 contexts poisoned"))?;
+    if let Some(pkcs11) = contexts.get(module) {
+        return Ok(pkcs11.clone());
+    }
+
+    let pkcs11 = Pkcs11::new(module).context("load PKCS#11 module")?;
+    pkcs11.initialize(CInitializeArgs::OsThreads)?;
+    contexts.insert(module.to_string(), pkcs11.clone());
+    Ok(pkcs11)
+}
+
+/// A P-256 private key in a PKCS#11 token, usable by rcgen as a signer.
+struct Pkcs11KeyPair {
+    session: Mutex<Session>,
+    key: ObjectHandle,
+    public_key: Vec<u8>,
+}
+
+impl RemoteKeyPair for Pkcs11KeyPair {
+    fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, rcgen::Error> {
+        let digest = Sha256::digest(msg);
+        let session = self
+            .session
+            .lock()
+            .map_err(|_| rcgen::Error::RemoteKeyError)?;
+        let raw = session
+            .sign(&Mechanism::Ecdsa, self.key, &digest)
+            .map_err(|_| rcgen::Error::RemoteKeyError)?;
+
+        // The token returns `r || s`, while X.509 wants an ASN.1 signature.
+        let signature = Signature::from_slice(&raw).map_err(|_| rcgen::Error::RemoteKeyError)?;
+        Ok(signature.to_der().as_bytes().to_vec())
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        &PKCS_ECDSA_P256_SHA256
+    }
+}
+
+pub struct Pkcs11CA {
+    inner: ManualCA,
+}
+
+impl Pkcs11CA {
+    /// Sign with the private key labeled `key_label` in the token of `slot`
+    /// of the PKCS#11 `module`. `public_key_cert` is the PEM of the CA
+    /// certificate of that key, optionally followed by its issuers.
+    pub fn new(
+        module: &str,
+        slot: u64,
+        key_label: &str,
+        pin: String,
+        public_key_cert: String,
+    ) -> Result<Self> {
+        let pkcs11 = context(module)?;
+        let slot = Slot::try_from(slot)?;
+        let session = pkcs11.open_ro_session(slot)?;
+        // The login is shared by all sessions of the token, e.g. that of the
+        // CA before a reload.
+        match session.login(UserType::User, Some(&AuthPin::new(pin))) {
+            Err(Error::Pkcs11(RvError::UserAlreadyLoggedIn)) => {}
+            result => result.context("login to PKCS#11 token")?,
+        }
+
+        let key = session
+            .find_objects(&[
+                Attribute::Class(ObjectClass::PRIVATE_KEY),
+                Attribute::KeyType(KeyType::EC),
+                Attribute::Label(key_label.as_bytes().to_vec()),
+            ])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no EC private key labeled {key_label} in the token"))?;
+
+        // The public key is taken from the CA certificate rather than the
+        // token, as not every token keeps the public key object around.
+        let pem = Pem::iter_from_buffer(public_key_cert.as_bytes())
+            .next()
+            .ok_or_else(|| anyhow!("no CA certificate"))?
+            .context("illegal CA certificate PEM")?;
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(&pem.contents).context("parse CA certificate")?;
+        let public_key = cert.public_key().subject_public_key.data.to_vec();
+        p256::PublicKey::from_sec1_bytes(&public_key)
+            .map_err(|_| anyhow!("CA certificate does not carry a P-256 public key"))?;
+
+        info!("CA signing key {key_label} found in PKCS#11 token");
+        let key_pair = KeyPair::from_remote(Box::new(Pkcs11KeyPair {
+            session: Mutex::new(session),
+            key,
+            public_key,
+        }))?;
+
+        Ok(Self {
+            inner: ManualCA::with_key_pair(key_pair, public_key_cert)?,
+        })
+    }
+
+    pub(super) async fn issue_cert(
+        &self,
+        id: &str,
+        csr: &str,
+        policy: &IssuancePolicy,
+        claims: &AttestationClaims,
+        ttl: i64,
+    ) -> Result<IssuedCert> {
+        self.inner.issue_cert(id, csr, policy, claims, ttl).await
+    }
+
+    pub(super) fn trust_bundle(&self) -> String {
+        self.inner.public_key_cert.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use cryptoki::object::AttributeType;
+    use kbs_types::Tee;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyUsagePurpose, SanType,
+    };
+
+    use super::*;
+    use crate::ca::{pem_to_der, CA};
+
+    /// DER of the P-256 curve OID.
+    const P256_PARAMS: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+    /// Needs a SoftHSM token labeled `aas-test`, e.g.
+    ///
+    /// ```sh
+    /// softhsm2-util --init-token --free --label aas-test --so-pin 1234 --pin 1234
+    /// AAS_TEST_PKCS11_PIN=1234 cargo test pkcs11 -- --ignored
+    /// ```
+    ///
+    /// The module defaults to `/usr/lib/softhsm/libsofthsm2.so` and can be
+    /// set by `AAS_TEST_PKCS11_MODULE`.
+    #[tokio::test]
+    #[ignore = "needs a SoftHSM token"]
+    async fn softhsm() {
+        let module = env::var("AAS_TEST_PKCS11_MODULE")
+            .unwrap_or_else(|_| "/usr/lib/softhsm/libsofthsm2.so".to_string());
+        let pin = env::var("AAS_TEST_PKCS11_PIN").unwrap_or_else(|_| "1234".to_string());
+        let label = format!("aas-test-{}", std::process::id());
+
+        let pkcs11 = context(&module).unwrap();
+        let slot = pkcs11
+            .get_slots_with_token()
+            .unwrap()
+            .into_iter()
+            .find(|slot| pkcs11.get_token_info(*slot).unwrap().label() == "aas-test")
+            .expect("no token labeled aas-test");
+        let session = pkcs11.open_rw_session(slot).unwrap();
+        session
+            .login(UserType::User, Some(&AuthPin::new(pin.clone())))
+            .unwrap();
+        let (public, private) = session
+            .generate_key_pair(
+                &Mechanism::EccKeyPairGen,
+                &[
+                    Attribute::Token(false),
+                    Attribute::EcParams(P256_PARAMS.to_vec()),
+                    Attribute::Verify(true),
+                ],
+                &[
+                    Attribute::Token(true),
+                    Attribute::Private(true),
+                    Attribute::Sensitive(true),
+                    Attribute::Sign(true),
+                    Attribute::Label(label.as_bytes().to_vec()),
+                ],
+            )
+            .unwrap();
+        let Some(Attribute::EcPoint(point)) = session
+            .get_attributes(public, &[AttributeType::EcPoint])
+            .unwrap()
+            .pop()
+        else {
+            panic!("no EC point of the generated key");
+        };
+        // The point is wrapped in a DER OCTET STRING.
+        let public_key = point[point.len() - 65..].to_vec();
+
+        // Self-sign the CA certificate with the key in the token.
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "AAS PKCS#11 Test CA");
+        params.key_pair = Some(
+            KeyPair::from_remote(Box::new(Pkcs11KeyPair {
+                session: Mutex::new(pkcs11.open_ro_session(slot).unwrap()),
+                key: private,
+                public_key,
+            }))
+            .unwrap(),
+        );
+        let ca_cert = Certificate::from_params(params)
+            .unwrap()
+            .serialize_pem()
+            .unwrap();
+
+        let ca = CA::Pkcs11(Pkcs11CA::new(&module, slot.id(), &label, pin, ca_cert).unwrap());
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = vec![SanType::URI("spiffe://w".to_string())];
+        let csr = Certificate::from_params(params)
+            .unwrap()
+            .serialize_request_pem()
+            .unwrap();
+        let claims = AttestationClaims {
+            tee: Tee::Sample,
+            claims: Default::default(),
+        };
+        let issued = ca
+            .issue_cert("spiffe://w", &csr, &Default::default(), &claims, 3600)
+            .await;
+        session.destroy_object(private).unwrap();
+        let issued = issued.unwrap();
+
+        let issued = pem_to_der(&issued.crt).unwrap().remove(0);
+        let ca_der = ca.trust_bundle_der().unwrap().remove(0);
+        let (_, ca_cert) = x509_parser::parse_x509_certificate(&ca_der).unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&issued).unwrap();
+        cert.verify_signature(Some(ca_cert.public_key())).unwrap();
+        assert_eq!(
+            AttestationClaims::from_cert_der(&issued).unwrap(),
+            Some(claims)
+        );
+    }
+}
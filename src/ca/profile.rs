@@ -0,0 +1,179 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! What an identity may get in its certificates. By default only client
+//! certificates without DNS or IP SANs are issued.
+
+use std::net::IpAddr;
+
+use anyhow::*;
+use rcgen::{CertificateParams, ExtendedKeyUsagePurpose, SanType};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CertProfile {
+    /// TLS client authentication, e.g. mTLS towards the AAS.
+    #[default]
+    Client,
+    /// TLS server authentication, for workloads serving TLS themselves.
+    Server,
+    Both,
+}
+
+impl CertProfile {
+    /// Whether an identity registered with this profile may request
+    /// `requested`.
+    pub fn permits(self, requested: CertProfile) -> bool {
+        self == CertProfile::Both || self == requested
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IssuancePolicy {
+    #[serde(default)]
+    pub profile: CertProfile,
+    /// DNS SANs the CSR may request.
+    #[serde(default)]
+    pub dns_names: Vec<String>,
+    /// IP SANs the CSR may request.
+    #[serde(default)]
+    pub ip_addresses: Vec<IpAddr>,
+}
+
+impl IssuancePolicy {
+    /// Whether only client certificates without DNS or IP SANs are issued.
+    pub fn is_default(&self) -> bool {
+        self.profile == CertProfile::Client
+            && self.dns_names.is_empty()
+            && self.ip_addresses.is_empty()
+    }
+
+    /// Reject the SANs of `params` that are not permitted, and set the
+    /// extended key usages of the profile. Exactly one URI SAN, `id`, is
+    /// required, so that the certificate names the attested identity.
+    pub(crate) fn apply(&self, id: &str, params: &mut CertificateParams) -> Result<()> {
+        let mut uris = 0;
+        for san in &params.subject_alt_names {
+            match san {
+                SanType::URI(uri) => {
+                    if uri != id {
+                        bail!("URI SAN {uri} is not the attested id {id}");
+                    }
+                    uris += 1;
+                }
+                SanType::DnsName(name) => {
+                    if !self
+                        .dns_names
+                        .iter()
+                        .any(|permitted| permitted.eq_ignore_ascii_case(name))
+                    {
+                        bail!("DNS SAN {name} is not permitted");
+                    }
+                }
+                SanType::IpAddress(ip) => {
+                    if !self.ip_addresses.contains(ip) {
+                        bail!("IP SAN {ip} is not permitted");
+                    }
+                }
+                other => bail!("SAN {other:?} is not permitted"),
+            }
+        }
+        if uris != 1 {
+            bail!("exactly one URI SAN {id} is required");
+        }
+
+        let purposes: &[ExtendedKeyUsagePurpose] = match self.profile {
+            CertProfile::Client => &[ExtendedKeyUsagePurpose::ClientAuth],
+            CertProfile::Server => &[ExtendedKeyUsagePurpose::ServerAuth],
+            CertProfile::Both => &[
+                ExtendedKeyUsagePurpose::ClientAuth,
+                ExtendedKeyUsagePurpose::ServerAuth,
+            ],
+        };
+        params.extended_key_usages.extend_from_slice(purposes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "spiffe://w";
+
+    fn params(sans: Vec<SanType>) -> CertificateParams {
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = sans;
+        params
+    }
+
+    fn params_with(san: SanType) -> CertificateParams {
+        params(vec![SanType::URI(ID.to_string()), san])
+    }
+
+    fn policy(profile: CertProfile) -> IssuancePolicy {
+        IssuancePolicy {
+            profile,
+            dns_names: vec!["web.example.com".to_string()],
+            ip_addresses: vec!["10.0.0.8".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn attested_uri_san() {
+        let mut params = params(vec![SanType::URI(ID.to_string())]);
+        policy(CertProfile::Client).apply(ID, &mut params).unwrap();
+        assert_eq!(
+            params.extended_key_usages,
+            [ExtendedKeyUsagePurpose::ClientAuth]
+        );
+    }
+
+    #[test]
+    fn other_uri_san() {
+        let mut other = params(vec![SanType::URI("spiffe://other".to_string())]);
+        assert!(policy(CertProfile::Client).apply(ID, &mut other).is_err());
+
+        let mut both = params(vec![
+            SanType::URI(ID.to_string()),
+            SanType::URI("spiffe://other".to_string()),
+        ]);
+        assert!(policy(CertProfile::Client).apply(ID, &mut both).is_err());
+    }
+
+    #[test]
+    fn missing_uri_san() {
+        let mut none = params(vec![]);
+        assert!(policy(CertProfile::Client).apply(ID, &mut none).is_err());
+
+        let mut twice = params(vec![
+            SanType::URI(ID.to_string()),
+            SanType::URI(ID.to_string()),
+        ]);
+        assert!(policy(CertProfile::Client).apply(ID, &mut twice).is_err());
+    }
+
+    #[test]
+    fn permitted_sans() {
+        let mut params = params(vec![
+            SanType::URI(ID.to_string()),
+            SanType::DnsName("WEB.example.com".to_string()),
+            SanType::IpAddress("10.0.0.8".parse().unwrap()),
+        ]);
+        policy(CertProfile::Both).apply(ID, &mut params).unwrap();
+        assert_eq!(
+            params.extended_key_usages,
+            [
+                ExtendedKeyUsagePurpose::ClientAuth,
+                ExtendedKeyUsagePurpose::ServerAuth
+            ]
+        );
+
+        let mut dns = params_with(SanType::DnsName("other.example.com".to_string()));
+        assert!(policy(CertProfile::Server).apply(ID, &mut dns).is_err());
+        let mut ip = params_with(SanType::IpAddress("10.0.0.9".parse().unwrap()));
+        assert!(policy(CertProfile::Server).apply(ID, &mut ip).is_err());
+    }
+}
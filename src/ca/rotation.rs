@@ -0,0 +1,249 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A set of CA keys to roll the signing key without a restart.
+//!
+//! A new key is introduced as [`KeyState::VerifyOnly`], so that its
+//! certificate is published in the trust bundle before anything is signed
+//! with it. Promoting it makes it [`KeyState::Active`] and demotes the
+//! previous active key to verify-only, which keeps certificates issued by the
+//! old key verifiable until it is retired.
+
+use std::sync::Arc;
+
+use anyhow::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{pem_to_der, profile::IssuancePolicy, IssuedCert, CA};
+use crate::{builder::DEFAULT_CA_KEY_ID, ratls::AttestationClaims, token::TokenKey};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+    /// Signs new certificates and is published. Exactly one key is active.
+    Active,
+    /// Published in the trust bundle but not used to sign.
+    #[serde(alias = "verify-only")]
+    VerifyOnly,
+    /// Neither used nor published.
+    Retired,
+}
+
+#[derive(Clone)]
+pub struct CaKey {
+    pub id: String,
+    pub state: KeyState,
+    pub ca: Arc<CA>,
+    /// The CA key to sign access tokens with, if it is an extractable P-256
+    /// key.
+    token_key: Option<TokenKey>,
+    /// Added by [`KeyRing::add`] rather than from the configuration.
+    added: bool,
+}
+
+impl CaKey {
+    fn new(id: String, state: KeyState, ca: CA, added: bool) -> Self {
+        let token_key = ca
+            .signing_key_pem()
+            .and_then(|pem| TokenKey::new(&pem))
+            .ok();
+        Self {
+            id,
+            state,
+            ca: Arc::new(ca),
+            token_key,
+            added,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CaKeyInfo {
+    pub id: String,
+    pub state: KeyState,
+}
+
+pub struct KeyRing {
+    keys: Vec<CaKey>,
+    /// Promotions and retirements in the order they were made, see
+    /// [`KeyRing::keep_changes`].
+    changes: Vec<(String, KeyState)>,
+}
+
+impl KeyRing {
+    /// Create a key ring whose only key `id` is active.
+    pub fn new(id: String, ca: CA) -> Self {
+        Self {
+            keys: vec![CaKey::new(id, KeyState::Active, ca, false)],
+            changes: Vec::new(),
+        }
+    }
+
+    /// Create a key ring whose active key is `active` under
+    /// [`crate::builder::DEFAULT_CA_KEY_ID`], together with `others`.
+    pub fn with_keys(active: CA, others: Vec<(String, KeyState, CA)>) -> Result<Self> {
+        let mut ring = Self::new(DEFAULT_CA_KEY_ID.to_string(), active);
+        for (id, state, ca) in others {
+            ring.insert(CaKey::new(id, state, ca, false))?;
+        }
+
+        Ok(ring)
+    }
+
+    /// Introduce a new key. It can not be active; use [`KeyRing::promote`].
+    pub fn add(&mut self, id: String, state: KeyState, ca: CA) -> Result<()> {
+        self.insert(CaKey::new(id, state, ca, true))
+    }
+
+    fn insert(&mut self, key: CaKey) -> Result<()> {
+        if self.keys.iter().any(|other| other.id == key.id) {
+            bail!("CA key {} already exists", key.id);
+        }
+        if key.state == KeyState::Active {
+            bail!("a new CA key must be promoted to become active");
+        }
+
+        self.keys.push(key);
+        Ok(())
+    }
+
+    /// Carry the keys added and the promotions and retirements made on
+    /// `previous` over to this key ring, which replaces it. Those that no
+    /// longer apply are dropped with a warning.
+    pub fn keep_changes(&mut self, previous: &KeyRing) {
+        for key in previous.keys.iter().filter(|key| key.added) {
+            let mut key = key.clone();
+            if key.state == KeyState::Active {
+                key.state = KeyState::VerifyOnly;
+            }
+            if let Err(e) = self.insert(key) {
+                warn!("drop a CA key added at runtime: {e:#}");
+            }
+        }
+        for (id, state) in &previous.changes {
+            let result = match state {
+                KeyState::Active => self.promote(id),
+                KeyState::Retired => self.retire(id),
+                KeyState::VerifyOnly => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("drop a change of CA key {id} made at runtime: {e:#}");
+            }
+        }
+    }
+
+    /// Make `id` the active key. The previous active key becomes verify-only.
+    pub fn promote(&mut self, id: &str) -> Result<()> {
+        let Some(index) = self.keys.iter().position(|key| key.id == id) else {
+            bail!("no CA key {id}");
+        };
+        match self.keys[index].state {
+            KeyState::Active => return Ok(()),
+            KeyState::Retired => bail!("CA key {id} is retired"),
+            KeyState::VerifyOnly => {}
+        }
+        self.changes.push((id.to_string(), KeyState::Active));
+
+        for key in &mut self.keys {
+            if key.state == KeyState::Active {
+                key.state = KeyState::VerifyOnly;
+            }
+        }
+        self.keys[index].state = KeyState::Active;
+        Ok(())
+    }
+
+    /// Stop publishing `id`. The active key can not be retired.
+    pub fn retire(&mut self, id: &str) -> Result<()> {
+        let Some(key) = self.keys.iter_mut().find(|key| key.id == id) else {
+            bail!("no CA key {id}");
+        };
+        if key.state == KeyState::Active {
+            bail!("can not retire the active CA key {id}");
+        }
+
+        key.state = KeyState::Retired;
+        self.changes.push((id.to_string(), KeyState::Retired));
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<CaKeyInfo> {
+        self.keys
+            .iter()
+            .map(|key| CaKeyInfo {
+                id: key.id.clone(),
+                state: key.state,
+            })
+            .collect()
+    }
+
+    fn active_key(&self) -> &CaKey {
+        self.keys
+            .iter()
+            .find(|key| key.state == KeyState::Active)
+            .expect("a key ring always has an active key")
+    }
+
+    pub fn active(&self) -> &CA {
+        &self.active_key().ca
+    }
+
+    /// Whether `id` could sign access tokens once active.
+    pub fn signs_tokens(&self, id: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|key| key.id == id && key.token_key.is_some())
+    }
+
+    /// The active key to sign access tokens with.
+    pub fn active_token_key(&self) -> Result<&TokenKey> {
+        let key = self.active_key();
+        key.token_key.as_ref().ok_or_else(|| {
+            anyhow!(
+                "the active CA key {} is not an extractable P-256 key to sign access tokens",
+                key.id
+            )
+        })
+    }
+
+    /// Access token keys of the active and the verify-only keys.
+    pub fn token_keys(&self) -> impl Iterator<Item = &TokenKey> {
+        self.keys
+            .iter()
+            .filter(|key| key.state != KeyState::Retired)
+            .filter_map(|key| key.token_key.as_ref())
+    }
+
+    pub async fn issue_cert(
+        &self,
+        id: &str,
+        csr: &str,
+        policy: &IssuancePolicy,
+        claims: &AttestationClaims,
+        ttl: i64,
+    ) -> Result<IssuedCert> {
+        self.active().issue_cert(id, csr, policy, claims, ttl).await
+    }
+
+    /// Certificates of the active key followed by the verify-only ones.
+    pub fn trust_bundle(&self) -> Result<String> {
+        let mut bundle = self.active().trust_bundle()?;
+        for key in &self.keys {
+            if key.state == KeyState::VerifyOnly {
+                if !bundle.ends_with('\n') {
+                    bundle.push('\n');
+                }
+                bundle.push_str(&key.ca.trust_bundle()?);
+            }
+        }
+
+        Ok(bundle)
+    }
+
+    /// DER encoded certificates of [`KeyRing::trust_bundle`].
+    pub fn trust_bundle_der(&self) -> Result<Vec<Vec<u8>>> {
+        pem_to_der(&self.trust_bundle()?)
+    }
+}
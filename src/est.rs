@@ -0,0 +1,134 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encodings for the Enrollment over Secure Transport (RFC 7030) interface.
+//!
+//! EST has no challenge step of its own, so an enrolling workload first gets
+//! a nonce via `/rcar/auth` (or `/rcar/renew` to re-enroll), then puts its TEE
+//! evidence in the `challengePassword` attribute of the CSR. As the evidence
+//! can not cover the CSR that carries it, the evidence binds the nonce with
+//! the public key of the CSR instead, see [`EnrollmentRequest::binding`].
+//! Stock EST clients can thus not enroll without a wrapper getting the nonce.
+
+use anyhow::*;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use x509_parser::{
+    certification_request::X509CertificationRequest,
+    cri_attributes::ParsedCriAttribute,
+    extensions::{GeneralName, ParsedExtension},
+    prelude::FromDer,
+};
+
+use crate::ca::der_to_pem;
+
+/// DER of the OID 1.2.840.113549.1.7.1 (id-data).
+const OID_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+/// DER of the OID 1.2.840.113549.1.7.2 (id-signedData).
+const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+
+pub(crate) fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut tlv = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        tlv.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        tlv.push(0x80 | bytes.len() as u8);
+        tlv.extend(bytes);
+    }
+    tlv.extend_from_slice(content);
+    tlv
+}
+
+/// A degenerate, certs-only PKCS#7 SignedData (RFC 7030 section 4.1.3)
+/// carrying the DER encoded `certs`.
+pub fn certs_only(certs: &[Vec<u8>]) -> Vec<u8> {
+    let signed_data = [
+        // version
+        der_tlv(0x02, &[1]),
+        // digestAlgorithms
+        der_tlv(0x31, &[]),
+        // encapContentInfo
+        der_tlv(0x30, &der_tlv(0x06, OID_DATA)),
+        // certificates [0] IMPLICIT
+        der_tlv(0xa0, &certs.concat()),
+        // signerInfos
+        der_tlv(0x31, &[]),
+    ]
+    .concat();
+
+    der_tlv(
+        0x30,
+        &[
+            der_tlv(0x06, OID_SIGNED_DATA),
+            der_tlv(0xa0, &der_tlv(0x30, &signed_data)),
+        ]
+        .concat(),
+    )
+}
+
+/// The first URI SAN requested by a CSR, which is the identity it is for.
+pub(crate) fn csr_uri_san(request: &X509CertificationRequest) -> Option<String> {
+    request
+        .requested_extensions()
+        .into_iter()
+        .flatten()
+        .find_map(|extension| match extension {
+            ParsedExtension::SubjectAlternativeName(san) => {
+                san.general_names.iter().find_map(|name| match name {
+                    GeneralName::URI(uri) => Some(uri.to_string()),
+                    _ => None,
+                })
+            }
+            _ => None,
+        })
+}
+
+/// An EST enrollment request carrying TEE evidence.
+pub struct EnrollmentRequest {
+    /// The identity, taken from the URI SAN of the CSR.
+    pub id: String,
+    /// The TEE evidence, taken from the `challengePassword` of the CSR.
+    pub evidence: String,
+    /// Base64 of the DER SubjectPublicKeyInfo of the CSR. It takes the place
+    /// of the CSR in the runtime data bound to the evidence.
+    pub binding: String,
+    /// PEM of the CSR.
+    pub csr: String,
+}
+
+impl EnrollmentRequest {
+    /// Parse a DER encoded PKCS#10 CSR.
+    pub fn from_der(csr: &[u8]) -> Result<Self> {
+        let (_, request) =
+            X509CertificationRequest::from_der(csr).map_err(|e| anyhow!("illegal CSR: {e}"))?;
+        request
+            .verify_signature()
+            .map_err(|_| anyhow!("illegal CSR signature"))?;
+
+        let evidence = request
+            .certification_request_info
+            .iter_attributes()
+            .find_map(|attribute| match attribute.parsed_attribute() {
+                ParsedCriAttribute::ChallengePassword(password) => Some(password.0.clone()),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("no TEE evidence as challengePassword in CSR"))?;
+
+        let id = csr_uri_san(&request).ok_or_else(|| anyhow!("no URI SAN in CSR"))?;
+
+        let binding = STANDARD.encode(request.certification_request_info.subject_pki.raw);
+
+        Ok(Self {
+            id,
+            evidence,
+            binding,
+            csr: der_to_pem("CERTIFICATE REQUEST", csr),
+        })
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Append-only ledger of the issued certificates.
+//!
+//! Every entry records which certificate was issued to which identity after
+//! which attestation. When hash chaining is enabled, each entry carries the
+//! SHA-256 digest of itself and its predecessor, so that modifying or
+//! removing an entry breaks the chain.
+
+use anyhow::*;
+use chrono::{DateTime, Utc};
+use kbs_types::Tee;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::store::SessionStore;
+
+/// Attempts to append when other servers append to the same ledger.
+const APPEND_RETRIES: usize = 16;
+
+/// Entries fetched from the store at once when scanning the ledger.
+pub(crate) const LEDGER_PAGE: u64 = 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Position in the ledger, starting from 0.
+    pub index: u64,
+    pub serial: String,
+    pub id: String,
+    pub tee: Tee,
+    pub nonce: String,
+    pub policy_ids: Vec<String>,
+    /// Hex encoded SHA-256 of the TEE evidence.
+    pub evidence_digest: String,
+    /// Hex encoded SHA-256 of the attestation token returned by the AS.
+    pub as_token_digest: String,
+    pub issued_at: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    /// `hash` of the previous entry, if hash chaining is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Selects the ledger entries of an identity or of a certificate.
+#[derive(Clone, Debug, Default)]
+pub struct LedgerFilter {
+    pub id: Option<String>,
+    /// Hex encoded certificate serial number.
+    pub serial: Option<String>,
+}
+
+impl LedgerFilter {
+    fn is_empty(&self) -> bool {
+        self.id.is_none() && self.serial.is_none()
+    }
+
+    fn matches(&self, entry: &LedgerEntry) -> bool {
+        self.id.as_ref().is_none_or(|id| *id == entry.id)
+            && self
+                .serial
+                .as_ref()
+                .is_none_or(|serial| *serial == entry.serial)
+    }
+}
+
+/// What is known about an issuance before it is put in the ledger.
+pub(crate) struct Issuance<'a> {
+    pub serial: &'a str,
+    pub id: &'a str,
+    pub tee: Tee,
+    pub nonce: &'a str,
+    pub policy_ids: &'a [String],
+    pub evidence: &'a str,
+    pub as_token: &'a str,
+    pub not_after: DateTime<Utc>,
+}
+
+pub(crate) fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+impl LedgerEntry {
+    /// Digest over the entry without its own `hash`.
+    fn compute_hash(&self) -> Result<String> {
+        let mut entry = self.clone();
+        entry.hash = None;
+        Ok(hex_digest(&serde_json::to_vec(&entry)?))
+    }
+}
+
+#[derive(Default)]
+pub struct Ledger {
+    hash_chain: bool,
+}
+
+impl Ledger {
+    pub fn new(hash_chain: bool) -> Self {
+        Self { hash_chain }
+    }
+
+    pub(crate) async fn record(
+        &self,
+        store: &SessionStore,
+        issuance: Issuance<'_>,
+    ) -> Result<LedgerEntry> {
+        for _ in 0..APPEND_RETRIES {
+            let last = store.last_ledger_entry().await?;
+            let mut entry = LedgerEntry {
+                index: last.as_ref().map(|last| last.index + 1).unwrap_or(0),
+                serial: issuance.serial.to_string(),
+                id: issuance.id.to_string(),
+                tee: issuance.tee,
+                nonce: issuance.nonce.to_string(),
+                policy_ids: issuance.policy_ids.to_vec(),
+                evidence_digest: hex_digest(issuance.evidence.as_bytes()),
+                as_token_digest: hex_digest(issuance.as_token.as_bytes()),
+                issued_at: Utc::now(),
+                not_after: issuance.not_after,
+                prev_hash: None,
+                hash: None,
+            };
+            if self.hash_chain {
+                entry.prev_hash = last.and_then(|last| last.hash);
+                entry.hash = Some(entry.compute_hash()?);
+            }
+
+            if store.append_ledger_entry(&entry).await? {
+                return Ok(entry);
+            }
+
+            warn!(
+                "ledger entry {} taken by another server, retry",
+                entry.index
+            );
+        }
+
+        bail!("failed to append to the ledger")
+    }
+
+    /// At most `limit` entries matching `filter`, after skipping the first
+    /// `offset` matching ones. A filter scans the whole ledger.
+    pub(crate) async fn entries(
+        &self,
+        store: &SessionStore,
+        filter: &LedgerFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<LedgerEntry>> {
+        if filter.is_empty() {
+            return store.ledger_entries(offset, limit).await;
+        }
+
+        let mut entries = Vec::new();
+        let mut skipped = 0;
+        let mut index = 0;
+        while (entries.len() as u64) < limit {
+            let page = store.ledger_entries(index, LEDGER_PAGE).await?;
+            if page.is_empty() {
+                break;
+            }
+            index += page.len() as u64;
+            for entry in page.into_iter().filter(|entry| filter.matches(entry)) {
+                if skipped < offset {
+                    skipped += 1;
+                } else if (entries.len() as u64) < limit {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Check that `entries`, starting from the first one of the ledger, are
+    /// contiguous and their hash chain is intact. With hash chaining every
+    /// entry must be chained, so that removing the hashes is detected.
+    pub fn verify(&self, entries: &[LedgerEntry]) -> Result<()> {
+        let mut prev_hash = None;
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.index != index as u64 {
+                bail!("ledger entry {index} is missing");
+            }
+
+            let Some(hash) = &entry.hash else {
+                if self.hash_chain {
+                    bail!("ledger entry {index} is not chained");
+                }
+                prev_hash = None;
+                continue;
+            };
+            if entry.prev_hash != prev_hash {
+                bail!("ledger entry {index} does not follow its predecessor");
+            }
+            if *hash != entry.compute_hash()? {
+                bail!("ledger entry {index} has been modified");
+            }
+
+            prev_hash = Some(hash.clone());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local store with a ledger of `count` entries, alternately issued to
+    /// `w` and `x`.
+    async fn store(ledger: &Ledger, count: usize) -> SessionStore {
+        let store = SessionStore::default();
+        for i in 0..count {
+            ledger
+                .record(
+                    &store,
+                    Issuance {
+                        serial: &format!("{i:02x}"),
+                        id: if i % 2 == 0 { "w" } else { "x" },
+                        tee: Tee::Sample,
+                        nonce: "nonce",
+                        policy_ids: &["default".to_string()],
+                        evidence: "evidence",
+                        as_token: "a.b.c",
+                        not_after: Utc::now(),
+                    },
+                )
+                .await
+                .unwrap();
+        }
+        store
+    }
+
+    /// A ledger of `count` entries recorded in a local store.
+    async fn entries(ledger: &Ledger, count: usize) -> Vec<LedgerEntry> {
+        let store = store(ledger, count).await;
+        store.ledger_entries(0, count as u64).await.unwrap()
+    }
+
+    fn assert_rejected(ledger: &Ledger, entries: &[LedgerEntry], reason: &str) {
+        let error = ledger.verify(entries).unwrap_err().to_string();
+        assert!(error.contains(reason), "{error}");
+    }
+
+    #[tokio::test]
+    async fn intact() {
+        for hash_chain in [false, true] {
+            let ledger = Ledger::new(hash_chain);
+            let entries = entries(&ledger, 3).await;
+            assert_eq!(entries.len(), 3);
+            ledger.verify(&entries).unwrap();
+        }
+        Ledger::new(true).verify(&[]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn modified_entry() {
+        let ledger = Ledger::new(true);
+        let mut entries = entries(&ledger, 3).await;
+        entries[1].id = "intruder".to_string();
+        assert_rejected(&ledger, &entries, "entry 1 has been modified");
+    }
+
+    #[tokio::test]
+    async fn rehashed_entry() {
+        let ledger = Ledger::new(true);
+        let mut entries = entries(&ledger, 3).await;
+        entries[1].id = "intruder".to_string();
+        entries[1].hash = Some(entries[1].compute_hash().unwrap());
+        assert_rejected(&ledger, &entries, "entry 2 does not follow");
+    }
+
+    #[tokio::test]
+    async fn removed_entry() {
+        let ledger = Ledger::new(true);
+        let mut entries = entries(&ledger, 3).await;
+        entries.remove(1);
+        assert_rejected(&ledger, &entries, "entry 1 is missing");
+
+        // Renumbering the following entries breaks their hashes.
+        entries[1].index = 1;
+        assert_rejected(&ledger, &entries, "entry 1 does not follow");
+    }
+
+    #[tokio::test]
+    async fn swapped_entries() {
+        let ledger = Ledger::new(true);
+        let mut entries = entries(&ledger, 3).await;
+        entries.swap(1, 2);
+        assert_rejected(&ledger, &entries, "entry 1 is missing");
+    }
+
+    #[tokio::test]
+    async fn stripped_hashes() {
+        let ledger = Ledger::new(true);
+        let mut entries = entries(&ledger, 3).await;
+        for entry in &mut entries {
+            entry.hash = None;
+            entry.prev_hash = None;
+        }
+        assert_rejected(&ledger, &entries, "entry 0 is not chained");
+        Ledger::new(false).verify(&entries).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unchained_ledger() {
+        let ledger = Ledger::new(false);
+        let mut entries = entries(&ledger, 2).await;
+        assert!(entries.iter().all(|entry| entry.hash.is_none()));
+        assert_rejected(&Ledger::new(true), &entries, "entry 0 is not chained");
+
+        entries[1].index = 2;
+        assert_rejected(&ledger, &entries, "entry 1 is missing");
+    }
+
+    #[tokio::test]
+    async fn filtered_pages() {
+        let ledger = Ledger::default();
+        let store = store(&ledger, 2 * LEDGER_PAGE as usize + 10).await;
+        let serials = |entries: Vec<LedgerEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.serial)
+                .collect::<Vec<_>>()
+        };
+        let x = LedgerFilter {
+            id: Some("x".to_string()),
+            serial: None,
+        };
+
+        // Offset and limit count the matching entries only.
+        let page = ledger.entries(&store, &x, 0, 3).await.unwrap();
+        assert_eq!(serials(page), ["01", "03", "05"]);
+        let page = ledger.entries(&store, &x, 3, 2).await.unwrap();
+        assert_eq!(serials(page), ["07", "09"]);
+
+        // Across the pages fetched from the store.
+        let page = ledger
+            .entries(&store, &x, LEDGER_PAGE, u64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 5);
+        assert!(page.iter().all(|entry| entry.id == "x"));
+
+        let serial = LedgerFilter {
+            id: None,
+            serial: Some("802".to_string()),
+        };
+        let page = ledger.entries(&store, &serial, 0, 100).await.unwrap();
+        assert_eq!(serials(page), ["802"]);
+        let page = ledger.entries(&store, &serial, 1, 100).await.unwrap();
+        assert!(page.is_empty());
+
+        let page = ledger
+            .entries(&store, &LedgerFilter::default(), 4, 2)
+            .await
+            .unwrap();
+        assert_eq!(serials(page), ["04", "05"]);
+    }
+}
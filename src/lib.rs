@@ -2,8 +2,18 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod acme;
 pub mod attestation;
 pub mod builder;
 pub mod ca;
+pub mod est;
+pub mod ledger;
+pub mod limit;
+pub mod metrics;
+pub mod protocol;
+pub mod ratls;
 pub mod server;
 pub mod session;
+pub mod store;
+pub mod telemetry;
+pub mod token;
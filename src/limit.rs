@@ -0,0 +1,460 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Abuse protection of the RCAR endpoints: rate limits per identity and
+//! source address and per source address, a cap on concurrent verifications by the attestation
+//! service, and a lockout of identities whose evidence keeps being rejected
+//! from a source address. Other sources can still attest the identity, so
+//! that a client can not lock a workload out by posting bogus evidence. For
+//! the same reason, requests for an identity from one source do not use up
+//! the rate of the identity from the others.
+//!
+//! The state is kept in the process, so with several replicas each one
+//! enforces the limits on its own.
+
+use std::{
+    fmt,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use scc::HashMap;
+use serde::Deserialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Buckets are pruned once there are that many keys.
+const MAX_KEYS: usize = 100_000;
+
+/// A request refused by a limit. `retry_after` is when it may succeed again.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub limit: &'static str,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} limit exceeded, retry after {}s",
+            self.limit,
+            self.retry_after.as_secs().max(1)
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Rate {
+    /// Requests per second in the long run.
+    pub per_second: f64,
+    /// Requests allowed at once.
+    pub burst: u32,
+}
+
+impl Rate {
+    fn check(&self, name: &str) -> anyhow::Result<()> {
+        if !(self.per_second > 0.0 && self.per_second.is_finite()) {
+            bail!("limits.{name}.per_second must be positive");
+        }
+        if self.burst == 0 {
+            bail!("limits.{name}.burst must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// All limits are off by default.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Limits {
+    /// RCAR, EST and ACME requests per identity and source address.
+    pub per_identity: Option<Rate>,
+    /// The same requests per source address.
+    pub per_source: Option<Rate>,
+    /// Evidence verifications running at once.
+    pub max_concurrent_verifications: Option<usize>,
+    /// Lock an identity out of a source address after that many rejected
+    /// attestations in a row from there.
+    pub max_failed_attestations: Option<u32>,
+    /// Seconds an identity stays locked out.
+    #[serde(default = "default_lockout_secs")]
+    pub lockout_secs: u64,
+}
+
+impl Limits {
+    /// Check that the rates refill and allow requests.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if let Some(rate) = &self.per_identity {
+            rate.check("per_identity")?;
+        }
+        if let Some(rate) = &self.per_source {
+            rate.check("per_source")?;
+        }
+        Ok(())
+    }
+}
+
+fn default_lockout_secs() -> u64 {
+    300
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets by key.
+pub(crate) struct RateLimiter<K: Eq + Hash> {
+    rate: Rate,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for `key`, or return when the next one is available.
+    pub async fn check(&self, key: K) -> Result<(), Duration> {
+        let burst = f64::from(self.rate.burst);
+        if self.buckets.len() >= MAX_KEYS {
+            self.prune(burst).await;
+        }
+
+        let now = Instant::now();
+        let mut entry = self.buckets.entry_async(key).await.or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let bucket = entry.get_mut();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.rate.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.rate.per_second,
+        ))
+    }
+
+    /// Drop the buckets that would be full by now.
+    async fn prune(&self, burst: f64) {
+        let now = Instant::now();
+        let per_second = self.rate.per_second;
+        self.buckets
+            .retain_async(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second
+                    < burst
+            })
+            .await;
+    }
+}
+
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Consecutive rejected attestations by identity and source.
+pub(crate) struct Lockout {
+    max_failures: u32,
+    duration: Duration,
+    failures: HashMap<(String, String), Failures>,
+}
+
+impl Lockout {
+    pub fn new(max_failures: u32, duration: Duration) -> Self {
+        Self {
+            max_failures,
+            duration,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Return for how long `id` is still locked out of `source`, if it is.
+    pub async fn check(&self, id: &str, source: &str) -> Result<(), Duration> {
+        let key = (id.to_string(), source.to_string());
+        let now = Instant::now();
+        let locked_until = self
+            .failures
+            .read_async(&key, |_, failures| failures.locked_until)
+            .await
+            .flatten();
+        match locked_until {
+            Some(until) if until > now => Err(until - now),
+            Some(_) => {
+                self.failures.remove_async(&key).await;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Count a rejected attestation. Returns whether `id` got locked out of
+    /// `source`.
+    pub async fn failed(&self, id: &str, source: &str) -> bool {
+        if self.failures.len() >= MAX_KEYS {
+            self.prune().await;
+        }
+
+        let mut entry = self
+            .failures
+            .entry_async((id.to_string(), source.to_string()))
+            .await
+            .or_insert(Failures {
+                count: 0,
+                locked_until: None,
+            });
+        let failures = entry.get_mut();
+        failures.count += 1;
+        if failures.count < self.max_failures {
+            return false;
+        }
+
+        failures.locked_until = Some(Instant::now() + self.duration);
+        true
+    }
+
+    pub async fn succeeded(&self, id: &str, source: &str) {
+        self.failures
+            .remove_async(&(id.to_string(), source.to_string()))
+            .await;
+    }
+
+    /// Drop the failures of identities that are not locked out.
+    async fn prune(&self) {
+        let now = Instant::now();
+        self.failures
+            .retain_async(|_, failures| failures.locked_until.is_some_and(|until| until > now))
+            .await;
+    }
+}
+
+/// The limits of a [`crate::server::Server`].
+#[derive(Default)]
+pub(crate) struct Limiter {
+    per_identity: Option<RateLimiter<(String, String)>>,
+    per_source: Option<RateLimiter<String>>,
+    verifications: Option<Semaphore>,
+    lockout: Option<Lockout>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            per_identity: limits.per_identity.map(RateLimiter::new),
+            per_source: limits.per_source.map(RateLimiter::new),
+            verifications: limits.max_concurrent_verifications.map(Semaphore::new),
+            lockout: limits
+                .max_failed_attestations
+                .map(|max| Lockout::new(max, Duration::from_secs(limits.lockout_secs))),
+        }
+    }
+
+    /// Apply the lockout of `id` from `source` and the per-identity limits.
+    pub async fn check_identity(&self, id: &str, source: &str) -> Result<(), RateLimited> {
+        if let Some(lockout) = &self.lockout {
+            lockout
+                .check(id, source)
+                .await
+                .map_err(|retry_after| RateLimited {
+                    limit: "lockout",
+                    retry_after,
+                })?;
+        }
+
+        match &self.per_identity {
+            Some(limiter) => limiter
+                .check((id.to_string(), source.to_string()))
+                .await
+                .map_err(|retry_after| RateLimited {
+                    limit: "identity",
+                    retry_after,
+                }),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn check_source(&self, source: &str) -> Result<(), RateLimited> {
+        match &self.per_source {
+            Some(limiter) => limiter
+                .check(source.to_string())
+                .await
+                .map_err(|retry_after| RateLimited {
+                    limit: "source",
+                    retry_after,
+                }),
+            None => Ok(()),
+        }
+    }
+
+    /// A slot to call the attestation service in, if one is free.
+    pub fn verification(&self) -> Result<Option<SemaphorePermit<'_>>, RateLimited> {
+        match &self.verifications {
+            Some(semaphore) => semaphore.try_acquire().map(Some).map_err(|_| RateLimited {
+                limit: "concurrent_verifications",
+                retry_after: Duration::from_secs(1),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Count a rejected attestation of `id` from `source`. Returns whether
+    /// it got locked out.
+    pub async fn attestation_failed(&self, id: &str, source: &str) -> bool {
+        match &self.lockout {
+            Some(lockout) => lockout.failed(id, source).await,
+            None => false,
+        }
+    }
+
+    pub async fn attestation_succeeded(&self, id: &str, source: &str) {
+        if let Some(lockout) = &self.lockout {
+            lockout.succeeded(id, source).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_identity: Option<Rate>, max_failed_attestations: Option<u32>) -> Limiter {
+        Limiter::new(Limits {
+            per_identity,
+            per_source: None,
+            max_concurrent_verifications: Some(1),
+            max_failed_attestations,
+            lockout_secs: 300,
+        })
+    }
+
+    #[tokio::test]
+    async fn bucket_allows_burst() {
+        let limiter = RateLimiter::new(Rate {
+            per_second: 1.0,
+            burst: 2,
+        });
+        assert!(limiter.check("a").await.is_ok());
+        assert!(limiter.check("a").await.is_ok());
+        let retry_after = limiter.check("a").await.unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+
+        // Other keys have their own bucket.
+        assert!(limiter.check("b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn bucket_refills() {
+        let limiter = RateLimiter::new(Rate {
+            per_second: 100.0,
+            burst: 1,
+        });
+        assert!(limiter.check("a").await.is_ok());
+        assert!(limiter.check("a").await.is_err());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(limiter.check("a").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn identity_limit() {
+        let limiter = limiter(
+            Some(Rate {
+                per_second: 0.001,
+                burst: 1,
+            }),
+            None,
+        );
+        assert!(limiter.check_identity("w", "10.0.0.1").await.is_ok());
+        let limited = limiter.check_identity("w", "10.0.0.1").await.unwrap_err();
+        assert_eq!(limited.limit, "identity");
+        assert!(limiter.check_identity("v", "10.0.0.1").await.is_ok());
+        // Requests from one source do not use up the rate of the others.
+        assert!(limiter.check_identity("w", "10.0.0.2").await.is_ok());
+    }
+
+    #[test]
+    fn invalid_rates() {
+        let rate = |per_second, burst| Limits {
+            per_source: Some(Rate { per_second, burst }),
+            ..Default::default()
+        };
+        assert!(rate(10.0, 50).check().is_ok());
+        for (per_second, burst) in [
+            (0.0, 1),
+            (-1.0, 1),
+            (f64::NAN, 1),
+            (f64::INFINITY, 1),
+            (1.0, 0),
+        ] {
+            let error = rate(per_second, burst).check().unwrap_err();
+            assert!(
+                error.to_string().starts_with("limits.per_source"),
+                "{error}"
+            );
+        }
+        assert!(Limits::default().check().is_ok());
+    }
+
+    #[tokio::test]
+    async fn lockout_by_source() {
+        let limiter = limiter(None, Some(2));
+        assert!(!limiter.attestation_failed("w", "10.0.0.1").await);
+        assert!(limiter.attestation_failed("w", "10.0.0.1").await);
+
+        let limited = limiter.check_identity("w", "10.0.0.1").await.unwrap_err();
+        assert_eq!(limited.limit, "lockout");
+        // Bogus evidence from one source does not lock the others out.
+        assert!(limiter.check_identity("w", "10.0.0.2").await.is_ok());
+        assert!(limiter.check_identity("v", "10.0.0.1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn lockout_counts_failures_in_a_row() {
+        let limiter = limiter(None, Some(2));
+        assert!(!limiter.attestation_failed("w", "10.0.0.1").await);
+        limiter.attestation_succeeded("w", "10.0.0.1").await;
+        assert!(!limiter.attestation_failed("w", "10.0.0.1").await);
+        assert!(limiter.check_identity("w", "10.0.0.1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn lockout_expires() {
+        let lockout = Lockout::new(1, Duration::from_millis(10));
+        assert!(lockout.failed("w", "10.0.0.1").await);
+        assert!(lockout.check("w", "10.0.0.1").await.is_err());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(lockout.check("w", "10.0.0.1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_verifications() {
+        let limiter = limiter(None, None);
+        let permit = limiter.verification().unwrap();
+        assert!(permit.is_some());
+        assert!(limiter.verification().is_err());
+        drop(permit);
+        assert!(limiter.verification().is_ok());
+    }
+
+    #[tokio::test]
+    async fn no_limits() {
+        let limiter = Limiter::default();
+        for _ in 0..100 {
+            assert!(limiter.check_identity("w", "10.0.0.1").await.is_ok());
+            assert!(limiter.check_source("10.0.0.1").await.is_ok());
+            assert!(!limiter.attestation_failed("w", "10.0.0.1").await);
+        }
+        assert!(limiter.verification().unwrap().is_none());
+    }
+}
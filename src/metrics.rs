@@ -0,0 +1,141 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Prometheus metrics of a [`crate::server::Server`], exported in the text
+//! format by [`crate::server::Monitoring::metrics`].
+
+use std::collections::BTreeMap;
+
+use anyhow::*;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use serde::Serialize;
+
+/// Session states reported by `aas_sessions`.
+pub(crate) const SESSION_STATES: [&str; 6] = [
+    "unregistered",
+    "authed",
+    "renewing",
+    "attesting",
+    "attested",
+    "expired",
+];
+
+pub struct Metrics {
+    registry: Registry,
+    /// RCAR handshake steps by `step` (`auth`, `renew`, `attest`) and
+    /// `outcome` (`ok` or why it failed).
+    pub(crate) handshakes: IntCounterVec,
+    pub(crate) handshake_duration: HistogramVec,
+    /// Calls to the attestation service by `tee` and `outcome`.
+    pub(crate) as_calls: IntCounterVec,
+    pub(crate) as_duration: HistogramVec,
+    /// Issued certificates by `profile`.
+    pub(crate) certs_issued: IntCounterVec,
+    /// Resource fetches by `decision` (`allow` or `deny`).
+    pub(crate) resource_access: IntCounterVec,
+    /// Requests refused by `limit`, see [`crate::limit`].
+    pub(crate) rate_limited: IntCounterVec,
+    pub(crate) lockouts: IntCounter,
+    sessions: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("aas".into()), None)?;
+
+        let handshakes = IntCounterVec::new(
+            Opts::new("handshakes_total", "RCAR handshake steps by outcome"),
+            &["step", "outcome"],
+        )?;
+        let handshake_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "handshake_duration_seconds",
+                "Time to serve an RCAR handshake step",
+            ),
+            &["step"],
+        )?;
+        let as_calls = IntCounterVec::new(
+            Opts::new(
+                "attestation_service_calls_total",
+                "Evidence verifications by the attestation service",
+            ),
+            &["tee", "outcome"],
+        )?;
+        let as_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "attestation_service_duration_seconds",
+                "Latency of the attestation service",
+            ),
+            &["tee"],
+        )?;
+        let certs_issued = IntCounterVec::new(
+            Opts::new("certificates_issued_total", "Issued certificates"),
+            &["profile"],
+        )?;
+        let resource_access = IntCounterVec::new(
+            Opts::new("resource_access_total", "Resource fetches by decision"),
+            &["decision"],
+        )?;
+        let rate_limited = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Requests refused by a limit"),
+            &["limit"],
+        )?;
+        let lockouts = IntCounter::new(
+            "lockouts_total",
+            "Identities locked out after rejected attestations",
+        )?;
+        let sessions = IntGaugeVec::new(
+            Opts::new("sessions", "Registered identities by session state"),
+            &["state"],
+        )?;
+
+        registry.register(Box::new(handshakes.clone()))?;
+        registry.register(Box::new(handshake_duration.clone()))?;
+        registry.register(Box::new(as_calls.clone()))?;
+        registry.register(Box::new(as_duration.clone()))?;
+        registry.register(Box::new(certs_issued.clone()))?;
+        registry.register(Box::new(resource_access.clone()))?;
+        registry.register(Box::new(rate_limited.clone()))?;
+        registry.register(Box::new(lockouts.clone()))?;
+        registry.register(Box::new(sessions.clone()))?;
+
+        Ok(Self {
+            registry,
+            handshakes,
+            handshake_duration,
+            as_calls,
+            as_duration,
+            certs_issued,
+            resource_access,
+            rate_limited,
+            lockouts,
+            sessions,
+        })
+    }
+
+    pub(crate) fn handshake(&self, step: &str, outcome: &str) {
+        self.handshakes.with_label_values(&[step, outcome]).inc();
+    }
+
+    /// Encode all metrics, with `sessions` counted by state.
+    pub(crate) fn encode(&self, sessions: BTreeMap<&'static str, i64>) -> Result<String> {
+        for state in SESSION_STATES {
+            let count = sessions.get(state).copied().unwrap_or_default();
+            self.sessions.with_label_values(&[state]).set(count);
+        }
+
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+/// Label of a value that serializes as a plain string, e.g. a TEE type.
+pub(crate) fn label<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        std::result::Result::Ok(serde_json::Value::String(label)) => label,
+        _ => "unknown".into(),
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validation of RCAR requests: the KBS protocol version they speak and their
+//! `extra_params`.
+
+use std::fmt;
+
+use kbs_types::Request;
+use semver::Version;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::ca::profile::CertProfile;
+
+/// Lowest supported KBS protocol version.
+pub const MIN_VERSION: Version = Version::new(0, 1, 0);
+/// First unsupported KBS protocol version.
+pub const MAX_VERSION: Version = Version::new(0, 2, 0);
+
+/// Ids are SPIFFE-like URIs, which are at most that long.
+const MAX_ID_LEN: usize = 2048;
+
+/// A request the server cannot serve as sent.
+#[derive(Debug)]
+pub enum InvalidRequest {
+    UnsupportedVersion(String),
+    Malformed(String),
+}
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidRequest::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {version}, supported: >={MIN_VERSION}, <{MAX_VERSION}"
+            ),
+            InvalidRequest::Malformed(reason) => write!(f, "malformed request: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
+
+impl InvalidRequest {
+    /// Outcome of the handshake, see [`crate::metrics`].
+    pub(crate) fn outcome(&self) -> &'static str {
+        match self {
+            InvalidRequest::UnsupportedVersion(_) => "unsupported_version",
+            InvalidRequest::Malformed(_) => "malformed",
+        }
+    }
+}
+
+/// The `extra_params` of a [`Request`], a JSON object. Other fields, e.g.
+/// hints for other servers, are ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct ExtraParams {
+    /// Registered id of the client. Required by `/rcar/auth`.
+    pub id: Option<String>,
+    /// Certificate profile to issue, if narrower than the registered one.
+    pub profile: Option<CertProfile>,
+}
+
+/// The `extra_params` of a [`kbs_types::Challenge`], telling the client the
+/// protocol version the handshake continues with.
+pub fn challenge_params(version: &Version) -> String {
+    json!({ "version": version.to_string() }).to_string()
+}
+
+/// Check the protocol version of `request` and parse its `extra_params`,
+/// returning the negotiated version with them. No `extra_params` are the
+/// same as `{}`.
+pub fn parse(request: &Request) -> Result<(Version, ExtraParams), InvalidRequest> {
+    let version = Version::parse(&request.version)
+        .map_err(|_| InvalidRequest::UnsupportedVersion(request.version.clone()))?;
+    if version < MIN_VERSION || version >= MAX_VERSION {
+        return Err(InvalidRequest::UnsupportedVersion(request.version.clone()));
+    }
+
+    if request.extra_params.trim().is_empty() {
+        return Ok((version, ExtraParams::default()));
+    }
+    let extra_params: ExtraParams = serde_json::from_str(&request.extra_params)
+        .map_err(|e| InvalidRequest::Malformed(format!("extra_params: {e}")))?;
+    if let Some(id) = &extra_params.id {
+        validate_id(id)?;
+    }
+    Ok((version, extra_params))
+}
+
+/// Ids must be non-empty and printable without whitespace, as they end up in
+/// certificates and logs.
+pub fn validate_id(id: &str) -> Result<(), InvalidRequest> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        return Err(InvalidRequest::Malformed(format!(
+            "id must be 1 to {MAX_ID_LEN} bytes"
+        )));
+    }
+    if id.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(InvalidRequest::Malformed(
+            "id must not contain whitespace or control characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use kbs_types::Tee;
+
+    use super::*;
+
+    fn request(version: &str, extra_params: &str) -> Request {
+        Request {
+            version: version.to_string(),
+            tee: Tee::Sample,
+            extra_params: extra_params.to_string(),
+        }
+    }
+
+    fn parse_version(version: &str) -> Result<Version, InvalidRequest> {
+        parse(&request(version, "")).map(|(version, _)| version)
+    }
+
+    #[test]
+    fn version_range() {
+        assert_eq!(parse_version("0.1.0").unwrap(), MIN_VERSION);
+        assert_eq!(parse_version("0.1.9").unwrap(), Version::new(0, 1, 9));
+        for version in ["0.0.9", "0.2.0", "1.0.0", "0.1", "latest", ""] {
+            assert!(matches!(
+                parse_version(version),
+                Err(InvalidRequest::UnsupportedVersion(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn extra_params() {
+        let (_, params) = parse(&request("0.1.0", "")).unwrap();
+        assert!(params.id.is_none() && params.profile.is_none());
+
+        let (_, params) = parse(&request(
+            "0.1.0",
+            r#"{"id": "spiffe://w", "profile": "server", "hint": 1}"#,
+        ))
+        .unwrap();
+        assert_eq!(params.id.as_deref(), Some("spiffe://w"));
+        assert_eq!(params.profile, Some(CertProfile::Server));
+
+        for extra_params in ["[]", "{", r#"{"id": 1}"#, r#"{"id": "a b"}"#] {
+            assert!(matches!(
+                parse(&request("0.1.0", extra_params)),
+                Err(InvalidRequest::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn challenge_version() {
+        let params: serde_json::Value =
+            serde_json::from_str(&challenge_params(&Version::new(0, 1, 3))).unwrap();
+        assert_eq!(params, json!({ "version": "0.1.3" }));
+    }
+
+    #[test]
+    fn ids() {
+        assert!(validate_id("spiffe://example.org/w").is_ok());
+        assert!(validate_id(&"a".repeat(MAX_ID_LEN)).is_ok());
+        for id in [
+            String::new(),
+            "a".repeat(MAX_ID_LEN + 1),
+            "spiffe://w x".to_string(),
+            "spiffe://w\n".to_string(),
+            "spiffe://w\u{7f}".to_string(),
+        ] {
+            assert!(validate_id(&id).is_err());
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! RA-TLS style attestation claims carried by the issued certificates.
+//!
+//! Every issued certificate has an extension with the TEE type and the TCB
+//! claims (e.g. measurement digests) the attestation service derived from
+//! the evidence. With the `ratls` feature, [`verifier`] provides rustls
+//! verifiers that check the chain to the CA and evaluate a predicate over
+//! these claims.
+
+#[cfg(feature = "ratls")]
+pub mod verifier;
+
+use anyhow::*;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kbs_types::Tee;
+use rcgen::CustomExtension;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use x509_parser::der_parser::der::parse_der_utf8string;
+
+use crate::est::der_tlv;
+
+/// OID of the attestation extension. It is under the enterprise number
+/// reserved for documentation (RFC 5612) until one is registered.
+pub const ATTESTATION_EXTENSION_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 32473, 1, 1];
+
+/// Content of the attestation extension, a DER UTF8String of this in JSON.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AttestationClaims {
+    pub tee: Tee,
+    /// The `tcb-status` claims of the attestation service token.
+    #[serde(default)]
+    pub claims: Map<String, Value>,
+}
+
+impl AttestationClaims {
+    /// Take the claims from `as_token`, the JWT returned by the attestation
+    /// service. The token is not verified again.
+    pub(crate) fn from_as_token(tee: Tee, as_token: &str) -> Self {
+        let claims = as_token
+            .split('.')
+            .nth(1)
+            .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+            .and_then(|payload| serde_json::from_slice::<Value>(&payload).ok())
+            .and_then(|mut payload| match payload["tcb-status"].take() {
+                Value::Object(claims) => Some(claims),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        Self { tee, claims }
+    }
+
+    pub(crate) fn to_extension(&self) -> Result<CustomExtension> {
+        let content = der_tlv(0x0c, &serde_json::to_vec(self)?);
+        Ok(CustomExtension::from_oid_content(
+            ATTESTATION_EXTENSION_OID,
+            content,
+        ))
+    }
+
+    /// The claims of a DER encoded certificate, if it has the extension.
+    pub fn from_cert_der(cert: &[u8]) -> Result<Option<Self>> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).context("parse certificate")?;
+        let oid = ATTESTATION_EXTENSION_OID
+            .iter()
+            .map(|arc| arc.to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        let Some(extension) = cert
+            .extensions()
+            .iter()
+            .find(|extension| extension.oid.to_id_string() == oid)
+        else {
+            return Ok(None);
+        };
+
+        let (_, content) = parse_der_utf8string(extension.value)
+            .map_err(|e| anyhow!("illegal attestation extension: {e}"))?;
+        let content = content
+            .as_str()
+            .map_err(|e| anyhow!("illegal attestation extension: {e}"))?;
+        let claims = serde_json::from_str(content).context("illegal attestation claims")?;
+        Ok(Some(claims))
+    }
+}
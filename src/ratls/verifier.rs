@@ -0,0 +1,362 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! rustls verifiers accepting only certificates issued by this server whose
+//! attestation claims satisfy a predicate.
+
+use std::{io::Cursor, sync::Arc, time::SystemTime};
+
+use anyhow::*;
+use log::warn;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    server::{AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier},
+    Certificate, CertificateError, DistinguishedName, RootCertStore, ServerName,
+};
+
+use super::AttestationClaims;
+
+/// Decides whether a peer with these claims is trusted.
+pub type Predicate = Arc<dyn Fn(&AttestationClaims) -> bool + Send + Sync>;
+
+/// Trust anchors from the PEM trust bundle of the server, e.g. as served at
+/// `/.well-known/aas/ca.pem`.
+fn root_store(trust_bundle: &str) -> Result<RootCertStore> {
+    let certs = rustls_pemfile::certs(&mut Cursor::new(trust_bundle))?;
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        bail!("no CA certificate in the trust bundle");
+    }
+
+    Ok(roots)
+}
+
+fn check_claims(end_entity: &Certificate, predicate: &Predicate) -> Result<(), rustls::Error> {
+    let claims = match AttestationClaims::from_cert_der(&end_entity.0) {
+        Result::Ok(Some(claims)) => claims,
+        Result::Ok(None) => {
+            warn!("peer certificate has no attestation claims");
+            return Err(CertificateError::ApplicationVerificationFailure.into());
+        }
+        Err(e) => {
+            warn!("peer certificate has malformed attestation claims: {e:#}");
+            return Err(CertificateError::BadEncoding.into());
+        }
+    };
+
+    if !predicate(&claims) {
+        warn!("attestation claims of the peer are rejected");
+        return Err(CertificateError::ApplicationVerificationFailure.into());
+    }
+
+    Result::Ok(())
+}
+
+/// Verifies the certificate of a TLS server, for use in a rustls client.
+pub struct AttestedServerCertVerifier {
+    inner: WebPkiVerifier,
+    predicate: Predicate,
+}
+
+impl AttestedServerCertVerifier {
+    pub fn new(trust_bundle: &str, predicate: Predicate) -> Result<Self> {
+        Ok(Self {
+            inner: WebPkiVerifier::new(root_store(trust_bundle)?, None),
+            predicate,
+        })
+    }
+}
+
+impl ServerCertVerifier for AttestedServerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        check_claims(end_entity, &self.predicate)?;
+        Result::Ok(verified)
+    }
+}
+
+/// Verifies the certificate of a TLS client, for use in a rustls server.
+pub struct AttestedClientCertVerifier {
+    inner: AllowAnyAuthenticatedClient,
+    predicate: Predicate,
+}
+
+impl AttestedClientCertVerifier {
+    pub fn new(trust_bundle: &str, predicate: Predicate) -> Result<Self> {
+        Ok(Self {
+            inner: AllowAnyAuthenticatedClient::new(root_store(trust_bundle)?),
+            predicate,
+        })
+    }
+}
+
+impl ClientCertVerifier for AttestedClientCertVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        check_claims(end_entity, &self.predicate)?;
+        Result::Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kbs_types::Tee;
+    use rcgen::{
+        BasicConstraints, Certificate as RcgenCertificate, CertificateParams, CustomExtension,
+        DnType, ExtendedKeyUsagePurpose, IsCa, KeyUsagePurpose, SanType,
+    };
+    use rustls::ServerName;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        ca::{
+            profile::{CertProfile, IssuancePolicy},
+            ManualCA, CA,
+        },
+        ratls::ATTESTATION_EXTENSION_OID,
+    };
+
+    const ID: &str = "spiffe://w";
+    const DNS_NAME: &str = "web.example.com";
+
+    /// A CA issuing through [`CA::issue_cert`], and its rcgen certificate to
+    /// sign certificates without going through it.
+    fn ca() -> (CA, RcgenCertificate) {
+        let mut params = CertificateParams::default();
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "AAS Test CA");
+        let signer = RcgenCertificate::from_params(params).unwrap();
+        let ca = ManualCA::new(
+            signer.serialize_private_key_pem(),
+            signer.serialize_pem().unwrap(),
+        )
+        .unwrap();
+        (CA::Manual(ca), signer)
+    }
+
+    fn claims() -> AttestationClaims {
+        AttestationClaims {
+            tee: Tee::Sample,
+            claims: json!({ "measurement": "aa" }).as_object().unwrap().clone(),
+        }
+    }
+
+    fn leaf_params() -> CertificateParams {
+        let mut params = CertificateParams::default();
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.subject_alt_names = vec![
+            SanType::URI(ID.to_string()),
+            SanType::DnsName(DNS_NAME.to_string()),
+        ];
+        params
+    }
+
+    async fn issue(ca: &CA) -> Certificate {
+        let csr = RcgenCertificate::from_params(leaf_params())
+            .unwrap()
+            .serialize_request_pem()
+            .unwrap();
+        let policy = IssuancePolicy {
+            profile: CertProfile::Both,
+            dns_names: vec![DNS_NAME.to_string()],
+            ip_addresses: Vec::new(),
+        };
+        let issued = ca
+            .issue_cert(ID, &csr, &policy, &claims(), 3600)
+            .await
+            .unwrap();
+        let der = rustls_pemfile::certs(&mut Cursor::new(issued.crt)).unwrap();
+        Certificate(der.into_iter().next().unwrap())
+    }
+
+    /// A certificate for the same key usages signed by the CA directly, with
+    /// `extension` instead of the claims.
+    fn sign(signer: &RcgenCertificate, extension: Option<CustomExtension>) -> Certificate {
+        let mut params = leaf_params();
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ClientAuth,
+            ExtendedKeyUsagePurpose::ServerAuth,
+        ];
+        params.custom_extensions.extend(extension);
+        let der = RcgenCertificate::from_params(params)
+            .unwrap()
+            .serialize_der_with_signer(signer)
+            .unwrap();
+        Certificate(der)
+    }
+
+    fn predicate(measurement: &'static str) -> Predicate {
+        Arc::new(move |claims: &AttestationClaims| {
+            claims.tee == Tee::Sample && claims.claims["measurement"] == measurement
+        })
+    }
+
+    fn verify_client(
+        ca: &CA,
+        predicate: Predicate,
+        cert: &Certificate,
+    ) -> Result<(), rustls::Error> {
+        let verifier =
+            AttestedClientCertVerifier::new(&ca.trust_bundle().unwrap(), predicate).unwrap();
+        verifier
+            .verify_client_cert(cert, &[], SystemTime::now())
+            .map(|_| ())
+    }
+
+    fn verify_server(
+        ca: &CA,
+        predicate: Predicate,
+        cert: &Certificate,
+    ) -> Result<(), rustls::Error> {
+        let verifier =
+            AttestedServerCertVerifier::new(&ca.trust_bundle().unwrap(), predicate).unwrap();
+        verifier
+            .verify_server_cert(
+                cert,
+                &[],
+                &ServerName::try_from(DNS_NAME).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    fn assert_rejected(result: Result<(), rustls::Error>, expected: CertificateError) {
+        match result {
+            Err(rustls::Error::InvalidCertificate(error)) => assert_eq!(error, expected),
+            other => panic!("expected {expected:?}, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_round_trip() {
+        let (ca, _) = ca();
+        let cert = issue(&ca).await;
+        assert_eq!(
+            AttestationClaims::from_cert_der(&cert.0).unwrap(),
+            Some(claims())
+        );
+
+        verify_client(&ca, predicate("aa"), &cert).unwrap();
+        verify_server(&ca, predicate("aa"), &cert).unwrap();
+        for result in [
+            verify_client(&ca, predicate("bb"), &cert),
+            verify_server(&ca, predicate("bb"), &cert),
+        ] {
+            assert_rejected(result, CertificateError::ApplicationVerificationFailure);
+        }
+    }
+
+    #[tokio::test]
+    async fn other_ca() {
+        let (other, _) = ca();
+        let (ca, _) = ca();
+        let cert = issue(&other).await;
+        // The other CA has the same name, but not the same key.
+        assert_rejected(
+            verify_client(&ca, predicate("aa"), &cert),
+            CertificateError::BadSignature,
+        );
+        assert_rejected(
+            verify_server(&ca, predicate("aa"), &cert),
+            CertificateError::BadSignature,
+        );
+    }
+
+    #[tokio::test]
+    async fn tampered_claims() {
+        let (ca, _) = ca();
+        let mut cert = issue(&ca).await;
+        let at = cert
+            .0
+            .windows(4)
+            .position(|window| window == b"\"aa\"")
+            .unwrap();
+        cert.0[at + 1..at + 3].copy_from_slice(b"bb");
+        assert_eq!(
+            AttestationClaims::from_cert_der(&cert.0)
+                .unwrap()
+                .unwrap()
+                .claims["measurement"],
+            "bb"
+        );
+
+        assert_rejected(
+            verify_client(&ca, predicate("bb"), &cert),
+            CertificateError::BadSignature,
+        );
+        assert_rejected(
+            verify_server(&ca, predicate("bb"), &cert),
+            CertificateError::BadSignature,
+        );
+    }
+
+    #[test]
+    fn missing_claims() {
+        let (ca, signer) = ca();
+        let cert = sign(&signer, None);
+        assert!(AttestationClaims::from_cert_der(&cert.0).unwrap().is_none());
+        let accept_all: Predicate = Arc::new(|_: &AttestationClaims| true);
+        assert_rejected(
+            verify_client(&ca, accept_all.clone(), &cert),
+            CertificateError::ApplicationVerificationFailure,
+        );
+        assert_rejected(
+            verify_server(&ca, accept_all, &cert),
+            CertificateError::ApplicationVerificationFailure,
+        );
+    }
+
+    #[test]
+    fn malformed_claims() {
+        let (ca, signer) = ca();
+        for content in [b"\x0c\x03{{{".to_vec(), b"\x04\x02{}".to_vec()] {
+            let extension = CustomExtension::from_oid_content(ATTESTATION_EXTENSION_OID, content);
+            let cert = sign(&signer, Some(extension));
+            assert!(AttestationClaims::from_cert_der(&cert.0).is_err());
+            let accept_all: Predicate = Arc::new(|_: &AttestationClaims| true);
+            assert_rejected(
+                verify_client(&ca, accept_all.clone(), &cert),
+                CertificateError::BadEncoding,
+            );
+            assert_rejected(
+                verify_server(&ca, accept_all, &cert),
+                CertificateError::BadEncoding,
+            );
+        }
+    }
+}
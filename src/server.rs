@@ -2,25 +2,90 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration as StdDuration,
+};
+
 use crate::{
-    attestation::AttestationService,
-    ca::CA,
-    session::{Attestation, Response, SessionStatus},
+    acme::{
+        self, account_key, problem, random_id, Account, AcmeBody, AcmeReply, ChallengeResponse,
+        Finalize, Jws, NewAccount, NewOrder, Order, Status, Urls,
+    },
+    attestation::{
+        routing::{Backends, DEFAULT_BACKEND},
+        Unavailable,
+    },
+    ca::der_to_pem,
+    ca::{
+        profile::{CertProfile, IssuancePolicy},
+        rotation::{CaKeyInfo, KeyRing, KeyState},
+        CA,
+    },
+    est::{csr_uri_san, EnrollmentRequest},
+    ledger::{Issuance, Ledger, LedgerEntry, LedgerFilter, LEDGER_PAGE},
+    limit::{Limiter, RateLimited},
+    metrics::{label, Metrics},
+    protocol::{self, InvalidRequest},
+    ratls::AttestationClaims,
+    session::{Attestation, Response, SessionStatus, TeePolicy},
+    store::{Identity, SessionStore},
+    telemetry,
+    token::{Claims, TokenIssuer},
 };
 
 use anyhow::*;
 use async_trait::async_trait;
-use kbs_types::{Challenge, Request};
-use log::info;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use kbs_types::{Challenge, Request, Tee};
+use log::{info, warn};
 // use rustls::server::{danger::ClientCertVerifier, WebPkiClientVerifier};
-use scc::{HashMap, HashSet};
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    sync::{Mutex, RwLock},
+    time::Instant,
+};
+use tracing::{info_span, instrument, Instrument, Span};
+use x509_parser::{certification_request::X509CertificationRequest, prelude::FromDer};
 
 #[async_trait]
 pub trait RCAR {
-    async fn request(&self, request: Request) -> Result<Challenge>;
+    /// `source` is the address of the client, e.g. an IP address, or empty
+    /// if unknown. Rejected attestations lock the id out of that source.
+    async fn request(&self, request: Request, source: &str) -> Result<Challenge>;
+
+    async fn attestation(&self, attestation: Attestation, source: &str) -> Result<Response>;
+
+    /// Start renewing `cert`. The caller must have authenticated its holder,
+    /// e.g. by mTLS. The evidence is then submitted via
+    /// [`RCAR::attestation`].
+    async fn renew(&self, request: Request, cert: &ClientCert, source: &str) -> Result<Challenge>;
+}
 
-    async fn attestation(&self, attestation: Attestation) -> Result<Response>;
+/// The answer to a challenge.
+struct Submission<'a> {
+    evidence: &'a str,
+    /// PEM CSR of the certificate to issue.
+    csr: &'a str,
+    /// Bound to the evidence together with the nonce.
+    binding: &'a str,
+}
+
+/// A certificate issued by this server, presented to renew it.
+#[derive(Clone, Debug)]
+pub struct ClientCert {
+    /// The identity in its URI SAN.
+    pub id: String,
+    /// Hex encoded serial number.
+    pub serial: String,
+    pub not_after: DateTime<Utc>,
 }
 
 #[async_trait]
@@ -30,68 +95,694 @@ pub trait AccessControl {
         id: &str,
         policy_ids: Vec<String>,
         allowed_resources: Vec<String>,
+        issuance: IssuancePolicy,
+        tees: TeePolicy,
     ) -> Result<()>;
 
     async fn get_resource(&self, rid: &str, id: &str) -> Result<Vec<u8>>;
+
+    /// Whether the certificate of `serial` has been revoked.
+    async fn is_revoked(&self, serial: &str) -> Result<bool>;
+
+    /// Verify an access token issued by [`RCAR::attestation`].
+    async fn verify_token(&self, token: &str) -> Result<Claims>;
 }
 
-#[derive(Debug)]
+/// Material that relying parties need to verify the issued certificates and
+/// access tokens.
+#[async_trait]
+pub trait Discovery {
+    /// PEM encoded CA certificates.
+    async fn trust_bundle(&self) -> Result<String>;
+
+    /// DER encoded CA certificates.
+    async fn trust_bundle_der(&self) -> Result<Vec<Vec<u8>>>;
+
+    /// JSON Web Key Set of the access token signing keys.
+    async fn jwks(&self) -> Result<Value>;
+
+    /// Issuer and audience of the access tokens, if enabled.
+    async fn token_info(&self) -> Option<(String, String)>;
+}
+
+/// Rolling of the CA signing key, see [`crate::ca::rotation`].
+#[async_trait]
+pub trait CaAdmin {
+    async fn list_ca_keys(&self) -> Vec<CaKeyInfo>;
+
+    /// Introduce a new key as verify-only.
+    async fn add_ca_key(&self, id: &str, ca: CA) -> Result<()>;
+
+    async fn promote_ca_key(&self, id: &str) -> Result<()>;
+
+    async fn retire_ca_key(&self, id: &str) -> Result<()>;
+}
+
+/// Queries over the ledger of issued certificates.
+#[async_trait]
+pub trait Audit {
+    /// At most `limit` entries matching `filter`, after skipping the first
+    /// `offset` matching ones.
+    async fn ledger_entries(
+        &self,
+        filter: &LedgerFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<LedgerEntry>>;
+
+    /// Check the whole ledger for missing or modified entries.
+    async fn verify_ledger(&self) -> Result<u64>;
+}
+
+/// Enrollment over Secure Transport (RFC 7030), see [`crate::est`].
+#[async_trait]
+pub trait Est {
+    /// Enroll with a DER encoded CSR carrying the TEE evidence. `source` is
+    /// as for [`RCAR::request`].
+    async fn enroll(&self, csr: &[u8], source: &str) -> Result<Response>;
+
+    /// Re-enroll with a DER encoded CSR carrying the TEE evidence. The caller
+    /// must have authenticated the holder of `cert`, e.g. by mTLS.
+    async fn reenroll(&self, csr: &[u8], cert: &ClientCert, source: &str) -> Result<Response>;
+}
+
+/// Operational state of the server.
+#[async_trait]
+pub trait Monitoring {
+    /// Metrics in the Prometheus text format, see [`crate::metrics`].
+    async fn metrics(&self) -> Result<String>;
+
+    /// Whether the CA, the session store and the attestation service can
+    /// serve handshakes. The result is reused for [`READINESS_TTL`], so that
+    /// frequent probes do not load the attestation services.
+    async fn readiness(&self) -> Readiness;
+}
+
+/// How long a [`Readiness`] is reused.
+pub const READINESS_TTL: StdDuration = StdDuration::from_secs(5);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// `ok` or the error, by component.
+    pub checks: BTreeMap<String, String>,
+}
+
+/// A subset of ACME (RFC 8555) with the `device-attest-01` challenge, see
+/// [`crate::acme`]. `base` is the scheme and authority the client reached the
+/// server at, and every JWS is checked to be posted to the matching URL.
+#[async_trait]
+pub trait Acme {
+    async fn acme_directory(&self, base: &str) -> Value;
+
+    /// A fresh anti-replay nonce.
+    async fn acme_nonce(&self) -> Result<String>;
+
+    async fn acme_new_account(&self, base: &str, jws: Jws) -> Result<AcmeReply>;
+
+    /// The account `id`, whose contacts may be updated.
+    async fn acme_account(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply>;
+
+    /// `source` is as for [`RCAR::request`].
+    async fn acme_new_order(&self, base: &str, jws: Jws, source: &str) -> Result<AcmeReply>;
+
+    async fn acme_order(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply>;
+
+    async fn acme_authorization(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply>;
+
+    /// Validate the TEE evidence of the `device-attest-01` challenge.
+    async fn acme_challenge(
+        &self,
+        base: &str,
+        id: &str,
+        jws: Jws,
+        source: &str,
+    ) -> Result<AcmeReply>;
+
+    async fn acme_finalize(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply>;
+
+    async fn acme_certificate(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Metadata {
     pub policy_ids: Vec<String>,
     pub allowed_resources: HashSet<String>,
+    /// What the certificates of the identity may be used for.
+    #[serde(default)]
+    pub issuance: IssuancePolicy,
+    /// Which TEEs the identity may attest with.
+    #[serde(default)]
+    pub tees: TeePolicy,
+}
+
+/// New handshakes are refused while the server shuts down, see
+/// [`Server::drain`].
+#[derive(Debug)]
+pub struct ShuttingDown;
+
+impl fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server is shutting down")
+    }
+}
+
+impl std::error::Error for ShuttingDown {}
+
+/// TEE evidence that passed the attestation service.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct VerifiedEvidence {
+    pub tee: Tee,
+    pub nonce: String,
+    pub evidence: String,
+    /// Token returned by the attestation service.
+    pub as_token: String,
 }
 
 pub struct Server {
-    pub(crate) ca: CA,
-    pub(crate) registered_identities: HashMap<String, (Metadata, SessionStatus)>,
-    pub(crate) attestation_service: AttestationService,
+    pub(crate) ca: RwLock<KeyRing>,
+    pub(crate) store: SessionStore,
+    pub(crate) token_issuer: Option<TokenIssuer>,
+    pub(crate) ledger: Ledger,
+    /// Replaced as a whole on reload, so that calls in progress can keep
+    /// using the previous one without holding the lock.
+    pub(crate) attestation_service: RwLock<Arc<Backends>>,
 
     pub(crate) attestation_timeout: i64,
+    /// Lifetime of the issued certificates in seconds.
+    pub(crate) cert_ttl: i64,
+    /// Seconds before their expiry from which certificates can be renewed.
+    pub(crate) renewal_window: i64,
+    /// Revoke the old certificate once it has been renewed.
+    pub(crate) revoke_renewed: bool,
+    pub(crate) metrics: Metrics,
+    pub(crate) draining: AtomicBool,
+    pub(crate) limiter: Limiter,
+    /// The last [`Readiness`] and when it was checked. Concurrent probes wait
+    /// for the one checking.
+    pub(crate) readiness: Mutex<Option<(Instant, Readiness)>>,
+}
+
+impl Server {
+    /// Refuse new handshakes with [`ShuttingDown`], and wait at most
+    /// `timeout` for those waiting for evidence to complete. Returns whether
+    /// they all did.
+    pub async fn drain(&self, timeout: StdDuration) -> Result<bool> {
+        self.draining.store(true, Ordering::SeqCst);
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let pending = self.store.pending_handshakes().await?;
+            if pending == 0 {
+                return Ok(true);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                warn!("{pending} handshakes still pending");
+                return Ok(false);
+            }
+            info!("waiting for {pending} pending handshakes");
+            tokio::time::sleep(StdDuration::from_millis(500)).await;
+        }
+    }
+
+    /// Persist the sessions and the ledger, see [`SessionStore::flush`].
+    pub async fn flush(&self) -> Result<()> {
+        self.store.flush().await
+    }
+
+    /// Replace the CA keys and the attestation services, e.g. after the
+    /// configuration changed. Sessions in progress are kept, and so are the
+    /// changes made via [`CaAdmin`], see [`KeyRing::keep_changes`].
+    pub async fn reload(&self, mut ca: KeyRing, attestation_service: Backends) -> Result<()> {
+        {
+            let mut current_ca = self.ca.write().await;
+            ca.keep_changes(&current_ca);
+            self.check_token_signer(&ca)?;
+            *current_ca = ca;
+        }
+        *self.attestation_service.write().await = Arc::new(attestation_service);
+        info!("CA keys and attestation service reloaded");
+        Ok(())
+    }
+
+    /// The attestation services as of now, see [`Server::reload`].
+    async fn backends(&self) -> Arc<Backends> {
+        self.attestation_service.read().await.clone()
+    }
+
+    /// Check that access tokens can be signed once `ca` is in use.
+    pub(crate) fn check_token_signer(&self, ca: &KeyRing) -> Result<()> {
+        if let Some(issuer) = &self.token_issuer {
+            issuer.signing_key(ca)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the per-source limits to a request from `source`, e.g. an IP
+    /// address, before serving it. See [`crate::limit`].
+    pub async fn limit_source(&self, source: &str) -> Result<()> {
+        self.limiter
+            .check_source(source)
+            .await
+            .inspect_err(|e| self.rate_limited(e))?;
+        Ok(())
+    }
+
+    /// Check that `cert` expires within the renewal window.
+    fn check_renewal_due(&self, cert: &ClientCert) -> Result<()> {
+        let due = cert.not_after - Duration::try_seconds(self.renewal_window).unwrap_or_default();
+        if Utc::now() < due {
+            bail!(
+                "certificate {} can not be renewed before {due}",
+                cert.serial
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that `identity` may get certificates of the `requested` profile.
+    fn check_profile(&self, identity: &Identity, requested: Option<CertProfile>) -> Result<()> {
+        let registered = identity.metadata.issuance.profile;
+        match requested {
+            Some(requested) if !registered.permits(requested) => {
+                bail!("profile {requested:?} is not permitted for this id")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the lockout from `source` and the per-identity limits to `id`.
+    async fn limit_identity(&self, id: &str, source: &str) -> Result<()> {
+        self.limiter
+            .check_identity(id, source)
+            .await
+            .inspect_err(|e| self.rate_limited(e))?;
+        Ok(())
+    }
+
+    /// Count a rejected attestation of `id` from `source` towards its lockout.
+    async fn attestation_rejected(&self, id: &str, source: &str) {
+        if self.limiter.attestation_failed(id, source).await {
+            warn!("{id} locked out of {source} after rejected attestations");
+            self.metrics.lockouts.inc();
+        }
+    }
+
+    fn rate_limited(&self, e: &RateLimited) {
+        self.metrics
+            .rate_limited
+            .with_label_values(&[e.limit])
+            .inc();
+    }
+
+    /// Verify the evidence of `submission` against the pending challenge of
+    /// `id` from `source`, and issue a certificate for its CSR if it passes.
+    /// If `renewal` is given, the challenge must have been issued to renew
+    /// the certificate of that serial.
+    async fn verify_and_issue(
+        &self,
+        id: &str,
+        submission: Submission<'_>,
+        renewal: Option<&str>,
+        source: &str,
+    ) -> Result<Response> {
+        let _timer = self
+            .metrics
+            .handshake_duration
+            .with_label_values(&["attest"])
+            .start_timer();
+        let failed = |outcome| self.metrics.handshake("attest", outcome);
+        let Some(mut identity) = self.store.get(id).await.inspect_err(|_| failed("error"))? else {
+            failed("unregistered");
+            bail!("No this id!");
+        };
+        self.limit_identity(id, source)
+            .await
+            .inspect_err(|_| failed("rate_limited"))?;
+
+        let span = info_span!("rcar.attest", id);
+        telemetry::set_parent(&span, &identity.trace_context);
+        async move {
+            if !identity.session.is_challenged() {
+                failed("no_challenge");
+                bail!("attestation failed, because no challenge has been issued");
+            }
+            if identity.session.is_expired() {
+                failed("expired");
+                bail!("attestation failed, because the auth session is expired");
+            }
+            if renewal.is_some() && identity.session.old_serial() != renewal {
+                failed("wrong_renewal");
+                bail!("attestation failed, because the challenge is not for this renewal");
+            }
+
+            // Consume the challenge before calling the AS, so that concurrent
+            // attestations can not both use it.
+            let challenge = identity.session.take_challenge();
+            if !self
+                .store
+                .update(id, &mut identity)
+                .await
+                .inspect_err(|_| failed("error"))?
+            {
+                failed("no_challenge");
+                bail!("attestation failed, because the challenge is already used");
+            }
+
+            let result = self
+                .attest_challenge(id, &identity, &challenge, &submission, source)
+                .await;
+            match &result {
+                // Not the fault of the identity, so it may retry.
+                Err(e) if e.is::<RateLimited>() || e.is::<Unavailable>() => {
+                    identity.session = challenge
+                }
+                result => {
+                    if result.is_ok() {
+                        identity.session.attest();
+                    } else {
+                        identity.session.reset();
+                    }
+                    identity.trace_context.clear();
+                    identity.profile = None;
+                }
+            }
+            // A concurrent `/rcar/auth` has precedence.
+            if !self.store.update(id, &mut identity).await? {
+                warn!("session of {id} changed during its attestation");
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Verify the evidence of `submission` for the consumed `challenge` of
+    /// `identity`, and issue a certificate for its CSR if it passes.
+    async fn attest_challenge(
+        &self,
+        id: &str,
+        identity: &Identity,
+        challenge: &SessionStatus,
+        submission: &Submission<'_>,
+        source: &str,
+    ) -> Result<Response> {
+        let failed = |outcome| self.metrics.handshake("attest", outcome);
+        let verified = match self
+            .verify_evidence(
+                id,
+                &identity.metadata,
+                *challenge.tee(),
+                challenge.nonce(),
+                submission.evidence,
+                submission.binding,
+            )
+            .await
+        {
+            std::result::Result::Ok(verified) => verified,
+            Err(e) if e.is::<RateLimited>() => {
+                failed("rate_limited");
+                return Err(e);
+            }
+            // Not the fault of the identity, so no lockout.
+            Err(e) if e.is::<Unavailable>() => {
+                failed("as_unavailable");
+                return Err(e);
+            }
+            Err(e) => {
+                failed("rejected");
+                self.attestation_rejected(id, source).await;
+                return Err(e);
+            }
+        };
+        self.limiter.attestation_succeeded(id, source).await;
+
+        let mut metadata = identity.metadata.clone();
+        if let Some(profile) = identity.profile {
+            metadata.issuance.profile = profile;
+        }
+        let response = self
+            .issue(id, &metadata, &verified, submission.csr)
+            .await
+            .inspect_err(|_| failed("error"))?;
+        if let Some(old_serial) = challenge.old_serial() {
+            info!("certificate {old_serial} of {id} renewed");
+            if self.revoke_renewed {
+                self.store.revoke(old_serial).await?;
+            }
+        }
+        self.metrics.handshake("attest", "ok");
+        Ok(response)
+    }
+
+    /// Verify `evidence` of `tee` from `id` under the policies of `metadata`.
+    /// `nonce` and `binding` are expected in the runtime data of the evidence.
+    #[instrument(name = "attestation_service.verify", skip_all, fields(tee = ?tee))]
+    pub(crate) async fn verify_evidence(
+        &self,
+        id: &str,
+        metadata: &Metadata,
+        tee: Tee,
+        nonce: &str,
+        evidence: &str,
+        binding: &str,
+    ) -> Result<VerifiedEvidence> {
+        metadata.tees.check_tee(tee)?;
+        let _permit = self
+            .limiter
+            .verification()
+            .inspect_err(|e| self.rate_limited(e))?;
+        let tee_label = label(&tee);
+        let timer = self
+            .metrics
+            .as_duration
+            .with_label_values(&[&tee_label])
+            .start_timer();
+        let result = self
+            .backends()
+            .await
+            .verify(
+                id,
+                evidence,
+                metadata.policy_ids.iter().map(|id| &id[..]).collect(),
+                nonce,
+                binding,
+                tee,
+            )
+            .await;
+        timer.observe_duration();
+        let outcome = match &result {
+            std::result::Result::Ok(_) => "ok",
+            Err(e) if e.is::<Unavailable>() => "unavailable",
+            Err(_) => "rejected",
+        };
+        self.metrics
+            .as_calls
+            .with_label_values(&[&tee_label, outcome])
+            .inc();
+        let as_token = result?;
+
+        Ok(VerifiedEvidence {
+            tee,
+            nonce: nonce.to_string(),
+            evidence: evidence.to_string(),
+            as_token,
+        })
+    }
+
+    /// Issue a certificate for `csr` to `id` after its evidence passed, and
+    /// record it in the ledger.
+    pub(crate) async fn issue(
+        &self,
+        id: &str,
+        metadata: &Metadata,
+        verified: &VerifiedEvidence,
+        csr: &str,
+    ) -> Result<Response> {
+        let cert = self
+            .ca
+            .read()
+            .await
+            .issue_cert(
+                id,
+                csr,
+                &metadata.issuance,
+                &AttestationClaims::from_as_token(verified.tee, &verified.as_token),
+                self.cert_ttl,
+            )
+            .await?;
+        self.metrics
+            .certs_issued
+            .with_label_values(&[&label(&metadata.issuance.profile)])
+            .inc();
+        self.ledger
+            .record(
+                &self.store,
+                Issuance {
+                    serial: &cert.serial,
+                    id,
+                    tee: verified.tee,
+                    nonce: &verified.nonce,
+                    policy_ids: &metadata.policy_ids,
+                    evidence: &verified.evidence,
+                    as_token: &verified.as_token,
+                    not_after: cert.not_after,
+                },
+            )
+            .await?;
+        let token = match &self.token_issuer {
+            Some(issuer) => Some(issuer.issue(
+                &*self.ca.read().await,
+                id,
+                verified.tee,
+                metadata.allowed_resources.iter().cloned().collect(),
+            )?),
+            None => None,
+        };
+
+        Ok(Response { cert, token })
+    }
 }
 
 #[async_trait]
 impl RCAR for Server {
-    async fn request(&self, request: Request) -> Result<Challenge> {
+    #[instrument(name = "rcar.auth", skip_all, fields(id))]
+    async fn request(&self, request: Request, source: &str) -> Result<Challenge> {
         info!("RCAR request: {request:?}");
-        let extra_params: Value = serde_json::from_str(&request.extra_params)?;
-
-        let Some(id) = extra_params.get("id").and_then(|id| id.as_str()) else {
-            bail!("no id in request");
+        let _timer = self
+            .metrics
+            .handshake_duration
+            .with_label_values(&["auth"])
+            .start_timer();
+        let failed = |outcome| self.metrics.handshake("auth", outcome);
+        if self.draining.load(Ordering::SeqCst) {
+            failed("shutting_down");
+            bail!(ShuttingDown);
+        }
+        let (version, extra_params) =
+            protocol::parse(&request).inspect_err(|e| failed(e.outcome()))?;
+        let Some(id) = extra_params.id.as_deref() else {
+            failed("malformed");
+            bail!(InvalidRequest::Malformed(
+                "no id in extra_params".to_string()
+            ));
         };
+        Span::current().record("id", id);
 
-        let Some(mut meta) = self.registered_identities.get_async(id).await else {
+        let Some(mut identity) = self.store.get(id).await.inspect_err(|_| failed("error"))? else {
+            failed("unregistered");
             bail!("No this id!");
         };
+        self.limit_identity(id, source)
+            .await
+            .inspect_err(|_| failed("rate_limited"))?;
+        identity
+            .metadata
+            .tees
+            .check(&request)
+            .inspect_err(|_| failed("tee_not_allowed"))?;
+        self.check_profile(&identity, extra_params.profile)
+            .inspect_err(|_| failed("profile_not_allowed"))?;
 
-        let meta = meta.get_mut();
-        let challenge = meta.1.auth(request, self.attestation_timeout);
+        let mut challenge = identity.session.auth(request, self.attestation_timeout);
+        challenge.extra_params = protocol::challenge_params(&version);
+        identity.trace_context = telemetry::current_context();
+        identity.profile = extra_params.profile;
+        if !self
+            .store
+            .update(id, &mut identity)
+            .await
+            .inspect_err(|_| failed("error"))?
+        {
+            failed("conflict");
+            bail!("session of {id} changed concurrently, retry");
+        }
+        self.metrics.handshake("auth", "ok");
 
         Ok(challenge)
     }
 
-    async fn attestation(&self, attestation: Attestation) -> Result<Response> {
-        let Some(mut meta) = self.registered_identities.get_async(&attestation.id).await else {
+    async fn attestation(&self, attestation: Attestation, source: &str) -> Result<Response> {
+        let submission = Submission {
+            evidence: &attestation.tee_evidence,
+            csr: &attestation.csr,
+            binding: &attestation.csr,
+        };
+        self.verify_and_issue(&attestation.id, submission, None, source)
+            .await
+    }
+
+    #[instrument(name = "rcar.renew", skip(self, request, source))]
+    async fn renew(&self, request: Request, cert: &ClientCert, source: &str) -> Result<Challenge> {
+        let ClientCert { id, serial, .. } = cert;
+        info!("RCAR renewal of {id} with certificate {serial}");
+        let _timer = self
+            .metrics
+            .handshake_duration
+            .with_label_values(&["renew"])
+            .start_timer();
+        let failed = |outcome| self.metrics.handshake("renew", outcome);
+        if self.draining.load(Ordering::SeqCst) {
+            failed("shutting_down");
+            bail!(ShuttingDown);
+        }
+        let (version, extra_params) =
+            protocol::parse(&request).inspect_err(|e| failed(e.outcome()))?;
+        if extra_params
+            .id
+            .as_deref()
+            .is_some_and(|requested| requested != id)
+        {
+            failed("malformed");
+            bail!(InvalidRequest::Malformed(
+                "id in extra_params does not match the certificate".to_string()
+            ));
+        }
+        if self
+            .store
+            .is_revoked(serial)
+            .await
+            .inspect_err(|_| failed("error"))?
+        {
+            failed("revoked");
+            bail!("certificate {serial} has been revoked");
+        }
+        self.check_renewal_due(cert)
+            .inspect_err(|_| failed("too_early"))?;
+
+        let Some(mut identity) = self.store.get(id).await.inspect_err(|_| failed("error"))? else {
+            failed("unregistered");
             bail!("No this id!");
         };
+        self.limit_identity(id, source)
+            .await
+            .inspect_err(|_| failed("rate_limited"))?;
+        identity
+            .metadata
+            .tees
+            .check(&request)
+            .inspect_err(|_| failed("tee_not_allowed"))?;
+        self.check_profile(&identity, extra_params.profile)
+            .inspect_err(|_| failed("profile_not_allowed"))?;
 
-        let meta = meta.get_mut();
-        if meta.1.is_expired() {
-            bail!("attestation failed, because the auth session is expired");
+        let mut challenge =
+            identity
+                .session
+                .renew(request, self.attestation_timeout, serial.to_string());
+        challenge.extra_params = protocol::challenge_params(&version);
+        identity.trace_context = telemetry::current_context();
+        identity.profile = extra_params.profile;
+        if !self
+            .store
+            .update(id, &mut identity)
+            .await
+            .inspect_err(|_| failed("error"))?
+        {
+            failed("conflict");
+            bail!("session of {id} changed concurrently, retry");
         }
-        self.attestation_service
-            .verify(
-                &attestation.tee_evidence,
-                meta.0.policy_ids.iter().map(|id| &id[..]).collect(),
-                meta.1.nonce(),
-                &attestation.csr,
-                *meta.1.tee(),
-            )
-            .await?;
+        self.metrics.handshake("renew", "ok");
 
-        let csr = attestation.csr;
-        let crt = self.ca.issue_cert(&csr).await?;
-        meta.1.attest();
-        Ok(Response { crt })
+        Ok(challenge)
     }
 }
 
@@ -102,41 +793,928 @@ impl AccessControl for Server {
         id: &str,
         policy_ids: Vec<String>,
         allowed_resources: Vec<String>,
+        issuance: IssuancePolicy,
+        tees: TeePolicy,
     ) -> Result<()> {
-        if self.registered_identities.contains(id) {
-            bail!("id already registered");
-        }
-
-        let allowed_resources_set = HashSet::new();
-        allowed_resources.into_iter().for_each(|ar| {
-            let _ = allowed_resources_set.insert(ar);
-        });
+        protocol::validate_id(id)?;
         let metadata = Metadata {
             policy_ids,
-            allowed_resources: allowed_resources_set,
+            allowed_resources: allowed_resources.into_iter().collect(),
+            issuance,
+            tees,
         };
-        let _ = self.registered_identities.insert(
-            id.to_string(),
-            (metadata, SessionStatus::UnRegistered { id: id.to_string() }),
-        );
+        let identity = Identity {
+            metadata,
+            session: SessionStatus::UnRegistered { id: id.to_string() },
+            trace_context: Default::default(),
+            profile: None,
+            generation: 0,
+        };
+        if !self.store.insert(id, &identity).await? {
+            bail!("id already registered");
+        }
 
         Ok(())
     }
 
     async fn get_resource(&self, rid: &str, id: &str) -> Result<Vec<u8>> {
         info!("{id} wants to retrieve {rid}...");
-        let Some(state) = self.registered_identities.get(id) else {
+        let deny = || {
+            self.metrics
+                .resource_access
+                .with_label_values(&["deny"])
+                .inc()
+        };
+        let Some(identity) = self.store.get(id).await? else {
+            deny();
             bail!("no this user id");
         };
 
-        let state = state.get();
-
-        if !state.0.allowed_resources.contains(rid) {
+        if !identity.metadata.allowed_resources.contains(rid) {
+            deny();
             bail!("not authorizd");
         }
+        self.metrics
+            .resource_access
+            .with_label_values(&["allow"])
+            .inc();
 
         info!("resource {rid} retrieved!");
 
         Ok(vec![])
     }
+
+    async fn is_revoked(&self, serial: &str) -> Result<bool> {
+        self.store.is_revoked(serial).await
+    }
+
+    async fn verify_token(&self, token: &str) -> Result<Claims> {
+        let Some(issuer) = &self.token_issuer else {
+            bail!("access tokens are not enabled");
+        };
+
+        issuer.verify(&*self.ca.read().await, token)
+    }
+}
+
+#[async_trait]
+impl Discovery for Server {
+    async fn trust_bundle(&self) -> Result<String> {
+        self.ca.read().await.trust_bundle()
+    }
+
+    async fn trust_bundle_der(&self) -> Result<Vec<Vec<u8>>> {
+        self.ca.read().await.trust_bundle_der()
+    }
+
+    async fn jwks(&self) -> Result<Value> {
+        let keys = match &self.token_issuer {
+            Some(issuer) => issuer.jwks(&*self.ca.read().await)?,
+            None => Vec::new(),
+        };
+
+        Ok(json!({ "keys": keys }))
+    }
+
+    async fn token_info(&self) -> Option<(String, String)> {
+        self.token_issuer
+            .as_ref()
+            .map(|issuer| (issuer.issuer().to_string(), issuer.audience().to_string()))
+    }
+}
+
+#[async_trait]
+impl Monitoring for Server {
+    async fn metrics(&self) -> Result<String> {
+        let sessions = self.store.session_states().await?;
+        self.metrics.encode(sessions)
+    }
+
+    async fn readiness(&self) -> Readiness {
+        let mut last = self.readiness.lock().await;
+        if let Some((checked, readiness)) = &*last {
+            if checked.elapsed() < READINESS_TTL {
+                return readiness.clone();
+            }
+        }
+
+        let readiness = self.check_readiness().await;
+        *last = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+}
+
+impl Server {
+    async fn check_readiness(&self) -> Readiness {
+        let ca = self.ca.read().await.trust_bundle().map(|_| ());
+        let store = self.store.ping().await;
+        let backends = self.backends().await;
+        let probe = backends.probe().await;
+
+        let mut checks = BTreeMap::new();
+        let mut ready = true;
+        for (name, result) in [
+            ("ca", ca),
+            ("session_store", store),
+            ("attestation_routes", probe.routes),
+        ] {
+            let status = match result {
+                std::result::Result::Ok(()) => "ok".to_string(),
+                Err(e) => {
+                    ready = false;
+                    e.to_string()
+                }
+            };
+            checks.insert(name.to_string(), status);
+        }
+        // Reported, but a route may do without some of its backends.
+        for (name, result) in probe.backends {
+            let name = match name {
+                DEFAULT_BACKEND => "attestation_service".to_string(),
+                name => format!("attestation_service.{name}"),
+            };
+            let status = match result {
+                std::result::Result::Ok(()) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            };
+            checks.insert(name, status);
+        }
+
+        Readiness { ready, checks }
+    }
+}
+
+#[async_trait]
+impl CaAdmin for Server {
+    async fn list_ca_keys(&self) -> Vec<CaKeyInfo> {
+        self.ca.read().await.list()
+    }
+
+    async fn add_ca_key(&self, id: &str, ca: CA) -> Result<()> {
+        self.ca
+            .write()
+            .await
+            .add(id.to_string(), KeyState::VerifyOnly, ca)?;
+        info!("CA key {id} added");
+        Ok(())
+    }
+
+    async fn promote_ca_key(&self, id: &str) -> Result<()> {
+        let mut ca = self.ca.write().await;
+        if self
+            .token_issuer
+            .as_ref()
+            .is_some_and(TokenIssuer::uses_ca_keys)
+            && !ca.signs_tokens(id)
+        {
+            bail!("CA key {id} can not sign access tokens");
+        }
+        ca.promote(id)?;
+        info!("CA key {id} promoted to active");
+        Ok(())
+    }
+
+    async fn retire_ca_key(&self, id: &str) -> Result<()> {
+        self.ca.write().await.retire(id)?;
+        info!("CA key {id} retired");
+        Ok(())
+    }
+}
+
+impl Server {
+    /// Check the nonce, URL and signature of `jws`, and return the id and
+    /// the account of the signer. Only a new account is signed by its `jwk`,
+    /// the other requests are signed by the `kid` of an existing account.
+    async fn acme_verify(
+        &self,
+        urls: &Urls<'_>,
+        url: &str,
+        jws: &Jws,
+        new_account: bool,
+    ) -> Result<(String, Account)> {
+        let protected = jws.protected()?;
+        if protected.alg != "ES256" {
+            bail!(problem(
+                "badSignatureAlgorithm",
+                format!("unsupported JWS algorithm {}", protected.alg)
+            ));
+        }
+        if protected.url != url {
+            bail!(problem("unauthorized", "JWS is not for this URL"));
+        }
+        if !self.store.take_acme_nonce(&protected.nonce).await? {
+            bail!(problem("badNonce", "unknown or used nonce"));
+        }
+
+        let (id, account) = match (protected.jwk, protected.kid) {
+            (Some(jwk), None) if new_account => {
+                let (_, id) = account_key(&jwk)?;
+                let account = Account {
+                    key: jwk,
+                    contact: Vec::new(),
+                };
+                (id, account)
+            }
+            (None, Some(kid)) if !new_account => {
+                let id = kid
+                    .strip_prefix(&urls.account(""))
+                    .ok_or_else(|| problem("malformed", "illegal kid"))?;
+                let Some(account) = self.store.get_acme(&format!("account:{id}")).await? else {
+                    bail!(problem("accountDoesNotExist", format!("no account {id}")));
+                };
+                (id.to_string(), serde_json::from_str(&account)?)
+            }
+            _ => bail!(problem(
+                "malformed",
+                "exactly one of jwk (new account) or kid (otherwise) is expected"
+            )),
+        };
+
+        let (key, _) = account_key(&account.key)?;
+        jws.verify(&key)?;
+        Ok((id, account))
+    }
+
+    /// Load order `id` of `account`.
+    async fn load_acme_order(&self, account: &str, id: &str) -> Result<Order> {
+        Ok(self.load_stored_acme_order(account, id).await?.0)
+    }
+
+    /// Load order `id` of `account`, together with its stored serialization
+    /// to be replaced by [`Server::replace_acme_order`].
+    async fn load_stored_acme_order(&self, account: &str, id: &str) -> Result<(Order, String)> {
+        let Some(stored) = self.store.get_acme(&format!("order:{id}")).await? else {
+            bail!(problem("malformed", format!("no order {id}")));
+        };
+
+        let mut order: Order = serde_json::from_str(&stored)?;
+        if order.account != account {
+            bail!(problem("unauthorized", "order of another account"));
+        }
+        order.check_expiry();
+        Ok((order, stored))
+    }
+
+    /// Orders are kept until they expire, valid ones as long as their
+    /// certificate, and failed ones `attestation_timeout` longer so that
+    /// clients can read the error.
+    fn acme_order_ttl(&self, order: &Order) -> u64 {
+        let ttl = match order.status {
+            Status::Valid => self.cert_ttl,
+            _ => (order.expires - Utc::now()).num_seconds() + self.attestation_timeout,
+        };
+        ttl.max(1) as u64
+    }
+
+    async fn save_acme_order(&self, id: &str, order: &Order) -> Result<()> {
+        self.store
+            .put_acme(
+                &format!("order:{id}"),
+                &serde_json::to_string(order)?,
+                Some(self.acme_order_ttl(order)),
+            )
+            .await
+    }
+
+    /// Replace order `id` by `order` only if it is still stored as `current`,
+    /// and return what is stored then.
+    async fn replace_acme_order(
+        &self,
+        id: &str,
+        current: &str,
+        order: &Order,
+    ) -> Result<Option<String>> {
+        let value = serde_json::to_string(order)?;
+        let replaced = self
+            .store
+            .replace_acme(
+                &format!("order:{id}"),
+                current,
+                &value,
+                self.acme_order_ttl(order),
+            )
+            .await?;
+        Ok(replaced.then_some(value))
+    }
+}
+
+/// Outstanding ACME nonces, beyond which the oldest are dropped. Clients
+/// retry with a fresh nonce on `badNonce`.
+const MAX_ACME_NONCES: usize = 10_000;
+
+#[async_trait]
+impl Acme for Server {
+    async fn acme_directory(&self, base: &str) -> Value {
+        let urls = Urls { base };
+        json!({
+            "newNonce": urls.new_nonce(),
+            "newAccount": urls.new_account(),
+            "newOrder": urls.new_order(),
+            "meta": { "externalAccountRequired": false },
+        })
+    }
+
+    async fn acme_nonce(&self) -> Result<String> {
+        let nonce = random_id();
+        self.store
+            .add_acme_nonce(
+                &nonce,
+                self.attestation_timeout.max(1) as u64,
+                MAX_ACME_NONCES,
+            )
+            .await?;
+        Ok(nonce)
+    }
+
+    async fn acme_new_account(&self, base: &str, jws: Jws) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (id, mut account) = self
+            .acme_verify(&urls, &urls.new_account(), &jws, true)
+            .await?;
+        let request: NewAccount = jws
+            .payload()?
+            .ok_or_else(|| problem("malformed", "no payload"))?;
+
+        let key = format!("account:{id}");
+        let created = match self.store.get_acme(&key).await? {
+            Some(existing) => {
+                account = serde_json::from_str(&existing)?;
+                false
+            }
+            None if request.only_return_existing => {
+                bail!(problem("accountDoesNotExist", "no account of this key"))
+            }
+            None => {
+                account.contact = request.contact;
+                self.store
+                    .put_acme(&key, &serde_json::to_string(&account)?, None)
+                    .await?;
+                info!("ACME account {id} created");
+                true
+            }
+        };
+
+        Ok(AcmeReply {
+            created,
+            location: Some(urls.account(&id)),
+            body: AcmeBody::Json(account.to_json()),
+        })
+    }
+
+    async fn acme_account(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (signer, mut account) = self
+            .acme_verify(&urls, &urls.account(id), &jws, false)
+            .await?;
+        if signer != id {
+            bail!(problem("unauthorized", "account of another key"));
+        }
+
+        // POST-as-GET, or an update of the contacts.
+        if let Some(update) = jws.payload::<NewAccount>()? {
+            account.contact = update.contact;
+            self.store
+                .put_acme(
+                    &format!("account:{id}"),
+                    &serde_json::to_string(&account)?,
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(AcmeReply::json(account.to_json()))
+    }
+
+    async fn acme_new_order(&self, base: &str, jws: Jws, source: &str) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (account, _) = self
+            .acme_verify(&urls, &urls.new_order(), &jws, false)
+            .await?;
+        let request: NewOrder = jws
+            .payload()?
+            .ok_or_else(|| problem("malformed", "no payload"))?;
+
+        let [identifier] = &request.identifiers[..] else {
+            bail!(problem(
+                "rejectedIdentifier",
+                "exactly one identifier is supported"
+            ));
+        };
+        if identifier.kind != acme::IDENTIFIER_TYPE {
+            bail!(problem(
+                "unsupportedIdentifier",
+                format!("only {} identifiers are supported", acme::IDENTIFIER_TYPE)
+            ));
+        }
+        if self.store.get(&identifier.value).await?.is_none() {
+            bail!(problem(
+                "rejectedIdentifier",
+                format!("{} is not registered", identifier.value)
+            ));
+        }
+        self.limit_identity(&identifier.value, source).await?;
+
+        let id = random_id();
+        let order = Order {
+            account,
+            id: identifier.value.clone(),
+            status: Status::Pending,
+            expires: Utc::now()
+                + Duration::try_seconds(self.attestation_timeout)
+                    .context("illegal attestation timeout")?,
+            token: random_id(),
+            verified: None,
+            validated: None,
+            error: None,
+            certificate: None,
+        };
+        self.save_acme_order(&id, &order).await?;
+        info!("ACME order {id} for {}", order.id);
+
+        Ok(AcmeReply {
+            created: true,
+            location: Some(urls.order(&id)),
+            body: AcmeBody::Json(order.to_json(&urls, &id)),
+        })
+    }
+
+    async fn acme_order(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (account, _) = self
+            .acme_verify(&urls, &urls.order(id), &jws, false)
+            .await?;
+        let order = self.load_acme_order(&account, id).await?;
+        Ok(AcmeReply::json(order.to_json(&urls, id)))
+    }
+
+    async fn acme_authorization(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (account, _) = self
+            .acme_verify(&urls, &urls.authorization(id), &jws, false)
+            .await?;
+        let order = self.load_acme_order(&account, id).await?;
+        Ok(AcmeReply::json(order.authorization_json(&urls, id)))
+    }
+
+    async fn acme_challenge(
+        &self,
+        base: &str,
+        id: &str,
+        jws: Jws,
+        source: &str,
+    ) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (account, account_object) = self
+            .acme_verify(&urls, &urls.challenge(id), &jws, false)
+            .await?;
+        let mut order = self.load_acme_order(&account, id).await?;
+
+        // POST-as-GET, or the challenge is not pending any more.
+        let response: Option<ChallengeResponse> = jws.payload()?;
+        let attestation = match response {
+            Some(response) if order.status == Status::Pending => response.attestation_object()?,
+            _ => None,
+        };
+        let Some(attestation) = attestation else {
+            return Ok(AcmeReply::json(order.challenge_json(&urls, id)));
+        };
+
+        let Some(identity) = self.store.get(&order.id).await? else {
+            bail!(problem("rejectedIdentifier", "id is no longer registered"));
+        };
+        self.limit_identity(&order.id, source).await?;
+        let (_, thumbprint) = account_key(&account_object.key)?;
+        let key_authorization = format!("{}.{thumbprint}", order.token);
+        match self
+            .verify_evidence(
+                &order.id,
+                &identity.metadata,
+                attestation.tee,
+                &order.token,
+                &attestation.evidence,
+                &key_authorization,
+            )
+            .await
+        {
+            Result::Ok(verified) => {
+                info!("ACME order {id} of {} attested", order.id);
+                self.limiter.attestation_succeeded(&order.id, source).await;
+                order.status = Status::Ready;
+                order.verified = Some(verified);
+                order.validated = Some(Utc::now());
+            }
+            // Not the fault of the client, so the order stays pending for a
+            // retry.
+            Err(e) if e.is::<RateLimited>() || e.is::<Unavailable>() => return Err(e),
+            Err(e) => {
+                warn!("ACME order {id} of {} failed attestation: {e:#}", order.id);
+                self.attestation_rejected(&order.id, source).await;
+                order.status = Status::Invalid;
+                order.error =
+                    Some(acme::Problem::new("badAttestationStatement", format!("{e:#}")).to_json());
+            }
+        }
+        self.save_acme_order(id, &order).await?;
+
+        Ok(AcmeReply::json(order.challenge_json(&urls, id)))
+    }
+
+    async fn acme_finalize(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (account, _) = self
+            .acme_verify(&urls, &urls.finalize(id), &jws, false)
+            .await?;
+        let (mut order, stored) = self.load_stored_acme_order(&account, id).await?;
+        let request: Finalize = jws
+            .payload()?
+            .ok_or_else(|| problem("malformed", "no payload"))?;
+
+        let (Status::Ready, Some(verified)) = (order.status, order.verified.clone()) else {
+            bail!(problem(
+                "orderNotReady",
+                "the challenge has not been validated"
+            ));
+        };
+
+        let csr = URL_SAFE_NO_PAD
+            .decode(&request.csr)
+            .map_err(|_| problem("badCSR", "CSR is not base64url"))?;
+        let (_, parsed) = X509CertificationRequest::from_der(&csr)
+            .map_err(|e| problem("badCSR", format!("illegal CSR: {e}")))?;
+        parsed
+            .verify_signature()
+            .map_err(|_| problem("badCSR", "illegal CSR signature"))?;
+        if csr_uri_san(&parsed).as_ref() != Some(&order.id) {
+            bail!(problem(
+                "badCSR",
+                format!("CSR must have {} as URI SAN", order.id)
+            ));
+        }
+
+        let Some(identity) = self.store.get(&order.id).await? else {
+            bail!(problem("rejectedIdentifier", "id is no longer registered"));
+        };
+
+        // Claim the order, so that a concurrent finalization can not get a
+        // second certificate.
+        order.status = Status::Processing;
+        let Some(processing) = self.replace_acme_order(id, &stored, &order).await? else {
+            bail!(problem("orderNotReady", "the order is being finalized"));
+        };
+        let response = match self
+            .issue(
+                &order.id,
+                &identity.metadata,
+                &verified,
+                &der_to_pem("CERTIFICATE REQUEST", &csr),
+            )
+            .await
+        {
+            std::result::Result::Ok(response) => response,
+            Err(e) => {
+                // Let the client finalize again.
+                order.status = Status::Ready;
+                if let Err(e) = self.replace_acme_order(id, &processing, &order).await {
+                    warn!("failed to reset ACME order {id}: {e:#}");
+                }
+                return Err(e);
+            }
+        };
+
+        let mut chain = response.cert.crt;
+        for cert in response.cert.chain {
+            if !chain.ends_with('\n') {
+                chain.push('\n');
+            }
+            chain.push_str(&cert);
+        }
+        order.certificate = Some(chain);
+        order.status = Status::Valid;
+        self.save_acme_order(id, &order).await?;
+
+        Ok(AcmeReply {
+            created: false,
+            location: Some(urls.order(id)),
+            body: AcmeBody::Json(order.to_json(&urls, id)),
+        })
+    }
+
+    async fn acme_certificate(&self, base: &str, id: &str, jws: Jws) -> Result<AcmeReply> {
+        let urls = Urls { base };
+        let (account, _) = self
+            .acme_verify(&urls, &urls.certificate(id), &jws, false)
+            .await?;
+        let order = self.load_acme_order(&account, id).await?;
+        let Some(certificate) = order.certificate else {
+            bail!(problem("malformed", "no certificate issued for this order"));
+        };
+
+        Ok(AcmeReply {
+            created: false,
+            location: None,
+            body: AcmeBody::PemChain(certificate),
+        })
+    }
+}
+
+#[async_trait]
+impl Audit for Server {
+    async fn ledger_entries(
+        &self,
+        filter: &LedgerFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<LedgerEntry>> {
+        self.ledger
+            .entries(&self.store, filter, offset, limit)
+            .await
+    }
+
+    async fn verify_ledger(&self) -> Result<u64> {
+        let mut entries = Vec::new();
+        loop {
+            let page = self
+                .store
+                .ledger_entries(entries.len() as u64, LEDGER_PAGE)
+                .await?;
+            if page.is_empty() {
+                break;
+            }
+            entries.extend(page);
+        }
+
+        self.ledger.verify(&entries)?;
+        Ok(entries.len() as u64)
+    }
+}
+
+#[async_trait]
+impl Est for Server {
+    async fn enroll(&self, csr: &[u8], source: &str) -> Result<Response> {
+        let request = EnrollmentRequest::from_der(csr)?;
+        info!("EST enrollment of {}", request.id);
+        let submission = Submission {
+            evidence: &request.evidence,
+            csr: &request.csr,
+            binding: &request.binding,
+        };
+        self.verify_and_issue(&request.id, submission, None, source)
+            .await
+    }
+
+    async fn reenroll(&self, csr: &[u8], cert: &ClientCert, source: &str) -> Result<Response> {
+        let request = EnrollmentRequest::from_der(csr)?;
+        let ClientCert { id, serial, .. } = cert;
+        info!("EST re-enrollment of {id} with certificate {serial}");
+        if request.id != *id {
+            bail!(
+                "CSR is for {}, but the client certificate is for {id}",
+                request.id
+            );
+        }
+        self.check_renewal_due(cert)?;
+
+        let submission = Submission {
+            evidence: &request.evidence,
+            csr: &request.csr,
+            binding: &request.binding,
+        };
+        self.verify_and_issue(&request.id, submission, Some(serial.as_str()), source)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::{
+        ecdsa::{signature::Signer, Signature, SigningKey},
+        PublicKey,
+    };
+    use rcgen::{Certificate, CertificateParams, SanType};
+
+    use super::*;
+    use crate::{
+        attestation::{AttestationService, Mock},
+        builder::ServerBuilder,
+        ca::SampleCA,
+    };
+
+    const BASE: &str = "https://aas.example";
+    const ID: &str = "spiffe://w";
+
+    fn server() -> Server {
+        ServerBuilder::new()
+            .with_ca(CA::Sample(SampleCA::new(None).unwrap()))
+            .with_attestation_service(AttestationService::Mock(Mock::Accept("a.b.c")))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn readiness_cached() {
+        let server = server();
+        assert!(server.readiness().await.ready);
+
+        let unavailable = AttestationService::Mock(Mock::Unavailable);
+        *server.attestation_service.write().await =
+            Arc::new(Backends::new(unavailable, vec![], vec![]).unwrap());
+        assert!(server.readiness().await.ready, "probed within the ttl");
+
+        if let Some((checked, _)) = server.readiness.lock().await.as_mut() {
+            *checked = Instant::now().checked_sub(READINESS_TTL).unwrap();
+        }
+        assert!(!server.readiness().await.ready);
+    }
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn jws(key: &SigningKey, nonce: &str, url: &str, kid: Option<&str>, payload: Value) -> Jws {
+        serde_json::from_value(jws_json(key, nonce, url, kid, payload)).unwrap()
+    }
+
+    /// A JWS of `payload`, or POST-as-GET if it is `null`, signed by `key`
+    /// and carrying its JWK unless `kid` is given.
+    fn jws_json(
+        key: &SigningKey,
+        nonce: &str,
+        url: &str,
+        kid: Option<&str>,
+        payload: Value,
+    ) -> Value {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match kid {
+            Some(kid) => protected["kid"] = kid.into(),
+            None => {
+                let jwk = PublicKey::from(key.verifying_key()).to_jwk();
+                protected["jwk"] = serde_json::to_value(jwk).unwrap();
+            }
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = match payload {
+            Value::Null => String::new(),
+            payload => URL_SAFE_NO_PAD.encode(payload.to_string()),
+        };
+        let signature: Signature = key.sign(format!("{protected}.{payload}").as_bytes());
+        json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        })
+    }
+
+    fn assert_problem(result: Result<AcmeReply>, kind: &str) {
+        let error = result.err().expect("request must fail");
+        let problem = error
+            .downcast_ref::<acme::Problem>()
+            .expect("not a problem");
+        assert_eq!(problem.kind, kind, "{}", problem.detail);
+    }
+
+    /// Create the account of `key` and return its URL.
+    async fn account(server: &Server, key: &SigningKey) -> String {
+        let urls = Urls { base: BASE };
+        let nonce = server.acme_nonce().await.unwrap();
+        let jws = jws(key, &nonce, &urls.new_account(), None, json!({}));
+        let reply = server.acme_new_account(BASE, jws).await.unwrap();
+        reply.location.unwrap()
+    }
+
+    #[tokio::test]
+    async fn acme_verify() {
+        let server = server();
+        let urls = Urls { base: BASE };
+        let key = key(1);
+        let kid = account(&server, &key).await;
+
+        let nonce = server.acme_nonce().await.unwrap();
+        let request = || jws(&key, &nonce, &kid, Some(&kid), Value::Null);
+        let id = kid.strip_prefix(&urls.account("")).unwrap();
+        server.acme_account(BASE, id, request()).await.unwrap();
+        // The nonce is used up.
+        assert_problem(server.acme_account(BASE, id, request()).await, "badNonce");
+        let unknown = jws(&key, "unknown", &kid, Some(&kid), Value::Null);
+        assert_problem(server.acme_account(BASE, id, unknown).await, "badNonce");
+
+        let nonce = server.acme_nonce().await.unwrap();
+        let other_url = jws(&key, &nonce, &urls.new_order(), Some(&kid), Value::Null);
+        assert_problem(
+            server.acme_account(BASE, id, other_url).await,
+            "unauthorized",
+        );
+
+        let nonce = server.acme_nonce().await.unwrap();
+        let other_key = jws(&self::key(2), &nonce, &kid, Some(&kid), Value::Null);
+        assert_problem(
+            server.acme_account(BASE, id, other_key).await,
+            "unauthorized",
+        );
+
+        let nonce = server.acme_nonce().await.unwrap();
+        let mut tampered = jws_json(&key, &nonce, &kid, Some(&kid), Value::Null);
+        tampered["payload"] = URL_SAFE_NO_PAD.encode(r#"{"contact":[]}"#).into();
+        let tampered = serde_json::from_value(tampered).unwrap();
+        assert_problem(
+            server.acme_account(BASE, id, tampered).await,
+            "unauthorized",
+        );
+    }
+
+    #[tokio::test]
+    async fn acme_finalize_once() {
+        let server = server();
+        server
+            .register_user(
+                ID,
+                vec!["default".to_string()],
+                Vec::new(),
+                Default::default(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let key = key(1);
+        let kid = account(&server, &key).await;
+        let urls = Urls { base: BASE };
+        let account_id = kid.strip_prefix(&urls.account("")).unwrap();
+
+        let order = |status| Order {
+            account: account_id.to_string(),
+            id: ID.to_string(),
+            status,
+            expires: Utc::now() + Duration::minutes(10),
+            token: "token".to_string(),
+            verified: Some(VerifiedEvidence {
+                tee: Tee::Sample,
+                nonce: "token".to_string(),
+                evidence: "evidence".to_string(),
+                as_token: "a.b.c".to_string(),
+            }),
+            validated: Some(Utc::now()),
+            error: None,
+            certificate: None,
+        };
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = vec![SanType::URI(ID.to_string())];
+        let csr = Certificate::from_params(params)
+            .unwrap()
+            .serialize_request_der()
+            .unwrap();
+        let finalize = |nonce: &str, id: &str| {
+            jws(
+                &key,
+                nonce,
+                &urls.finalize(id),
+                Some(&kid),
+                json!({ "csr": URL_SAFE_NO_PAD.encode(&csr) }),
+            )
+        };
+
+        server
+            .save_acme_order("o", &order(Status::Ready))
+            .await
+            .unwrap();
+        let nonce = server.acme_nonce().await.unwrap();
+        server
+            .acme_finalize(BASE, "o", finalize(&nonce, "o"))
+            .await
+            .unwrap();
+        let nonce = server.acme_nonce().await.unwrap();
+        assert_problem(
+            server.acme_finalize(BASE, "o", finalize(&nonce, "o")).await,
+            "orderNotReady",
+        );
+
+        // Claimed by a concurrent finalization.
+        server
+            .save_acme_order("p", &order(Status::Processing))
+            .await
+            .unwrap();
+        let nonce = server.acme_nonce().await.unwrap();
+        assert_problem(
+            server.acme_finalize(BASE, "p", finalize(&nonce, "p")).await,
+            "orderNotReady",
+        );
+
+        // Changed after it was loaded.
+        server
+            .save_acme_order("q", &order(Status::Ready))
+            .await
+            .unwrap();
+        let (_, stored) = server
+            .load_stored_acme_order(account_id, "q")
+            .await
+            .unwrap();
+        let processing = server
+            .replace_acme_order("q", &stored, &order(Status::Processing))
+            .await
+            .unwrap();
+        assert!(processing.is_some());
+        let again = server
+            .replace_acme_order("q", &stored, &order(Status::Processing))
+            .await
+            .unwrap();
+        assert!(again.is_none());
+    }
 }
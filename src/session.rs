@@ -2,14 +2,18 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+use anyhow::{bail, Context, Result};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use chrono::{DateTime, Duration, Utc};
 use kbs_types::{Challenge, Request, Tee};
 use log::warn;
 use rand::{thread_rng, Rng};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 
+use crate::ca::IssuedCert;
+
 fn nonce() -> String {
     let mut nonce: Vec<u8> = vec![0; 32];
 
@@ -28,11 +32,48 @@ pub struct Attestation {
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Response {
-    pub crt: String,
+    #[serde(flatten)]
+    pub cert: IssuedCert,
+    /// Signed access token, for clients that cannot use `crt` for mTLS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// Which TEEs an identity may attest with. Anything goes by default.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TeePolicy {
+    /// Any TEE type if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_tees: Vec<Tee>,
+    /// Lowest KBS protocol version of the requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_version: Option<Version>,
+}
+
+impl TeePolicy {
+    pub(crate) fn check_tee(&self, tee: Tee) -> Result<()> {
+        if !self.allowed_tees.is_empty() && !self.allowed_tees.contains(&tee) {
+            bail!("TEE {tee:?} is not allowed for this id");
+        }
+        Ok(())
+    }
+
+    /// Check the TEE and the protocol version of `request`.
+    pub(crate) fn check(&self, request: &Request) -> Result<()> {
+        self.check_tee(request.tee)?;
+        if let Some(min_version) = &self.min_version {
+            let version = Version::parse(&request.version)
+                .with_context(|| format!("invalid version {}", request.version))?;
+            if version < *min_version {
+                bail!("version {version} is older than {min_version}, required for this id");
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Finite State Machine model for RCAR handshake
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) enum SessionStatus {
     UnRegistered {
         id: String,
@@ -48,6 +89,23 @@ pub(crate) enum SessionStatus {
     Attested {
         id: String,
     },
+
+    /// The holder of a still valid certificate asked for a new one.
+    Renewing {
+        tee: Tee,
+        nonce: String,
+        id: String,
+        timeout: DateTime<Utc>,
+        /// Serial of the certificate that authenticated the renewal.
+        old_serial: String,
+    },
+
+    /// The evidence for the challenge is being verified. The challenge is
+    /// consumed, so that its nonce can not be used twice.
+    Attesting {
+        id: String,
+        timeout: DateTime<Utc>,
+    },
 }
 
 impl SessionStatus {
@@ -68,11 +126,31 @@ impl SessionStatus {
         }
     }
 
+    pub fn renew(&mut self, request: Request, timeout: i64, old_serial: String) -> Challenge {
+        let timeout = Utc::now() + Duration::try_seconds(timeout).unwrap();
+
+        let nonce = nonce();
+        *self = Self::Renewing {
+            tee: request.tee,
+            nonce: nonce.clone(),
+            timeout,
+            id: self.id().to_string(),
+            old_serial,
+        };
+
+        Challenge {
+            nonce,
+            extra_params: String::new(),
+        }
+    }
+
     pub fn nonce(&self) -> &str {
         match self {
             SessionStatus::UnRegistered { .. } => panic!("no nonce initialized"),
             SessionStatus::Authed { nonce, .. } => nonce,
             SessionStatus::Attested { .. } => panic!("no nonce initialized"),
+            SessionStatus::Renewing { nonce, .. } => nonce,
+            SessionStatus::Attesting { .. } => panic!("nonce already consumed"),
         }
     }
 
@@ -81,6 +159,8 @@ impl SessionStatus {
             SessionStatus::UnRegistered { .. } => panic!("no tee initialized"),
             SessionStatus::Authed { tee, .. } => tee,
             SessionStatus::Attested { .. } => panic!("no tee initialized"),
+            SessionStatus::Renewing { tee, .. } => tee,
+            SessionStatus::Attesting { .. } => panic!("tee already consumed"),
         }
     }
 
@@ -89,6 +169,24 @@ impl SessionStatus {
             SessionStatus::UnRegistered { id } => id,
             SessionStatus::Authed { id, .. } => id,
             SessionStatus::Attested { id, .. } => id,
+            SessionStatus::Renewing { id, .. } => id,
+            SessionStatus::Attesting { id, .. } => id,
+        }
+    }
+
+    /// Whether a challenge has been issued and waits for evidence.
+    pub fn is_challenged(&self) -> bool {
+        matches!(
+            self,
+            SessionStatus::Authed { .. } | SessionStatus::Renewing { .. }
+        )
+    }
+
+    /// Serial of the certificate being renewed, if this is a renewal.
+    pub fn old_serial(&self) -> Option<&str> {
+        match self {
+            SessionStatus::Renewing { old_serial, .. } => Some(old_serial),
+            _ => None,
         }
     }
 
@@ -97,12 +195,54 @@ impl SessionStatus {
             SessionStatus::UnRegistered { .. } => false,
             SessionStatus::Authed { timeout, .. } => *timeout < Utc::now(),
             SessionStatus::Attested { .. } => false,
+            SessionStatus::Renewing { timeout, .. } => *timeout < Utc::now(),
+            SessionStatus::Attesting { timeout, .. } => *timeout < Utc::now(),
         }
     }
 
+    /// Forget the pending challenge, if any.
+    pub fn reset(&mut self) {
+        *self = Self::UnRegistered {
+            id: self.id().to_string(),
+        };
+    }
+
+    /// State label of the session, one of [`crate::metrics::SESSION_STATES`].
+    pub fn state(&self) -> &'static str {
+        if self.is_expired() {
+            return "expired";
+        }
+
+        match self {
+            SessionStatus::UnRegistered { .. } => "unregistered",
+            SessionStatus::Authed { .. } => "authed",
+            SessionStatus::Attested { .. } => "attested",
+            SessionStatus::Renewing { .. } => "renewing",
+            SessionStatus::Attesting { .. } => "attesting",
+        }
+    }
+
+    /// Consume the pending challenge, which is returned, and wait for its
+    /// evidence to be verified.
+    pub fn take_challenge(&mut self) -> SessionStatus {
+        let timeout = match self {
+            SessionStatus::Authed { timeout, .. } | SessionStatus::Renewing { timeout, .. } => {
+                *timeout
+            }
+            _ => panic!("no challenge issued"),
+        };
+        let attesting = Self::Attesting {
+            id: self.id().to_string(),
+            timeout,
+        };
+        std::mem::replace(self, attesting)
+    }
+
     pub fn attest(&mut self) {
         match self {
-            SessionStatus::Authed { id, .. } => *self = Self::Attested { id: id.clone() },
+            SessionStatus::Authed { id, .. }
+            | SessionStatus::Renewing { id, .. }
+            | SessionStatus::Attesting { id, .. } => *self = Self::Attested { id: id.clone() },
             SessionStatus::Attested { .. } => {
                 warn!("session already attested.");
             }
@@ -0,0 +1,488 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::*;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use scc::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+use super::Identity;
+use crate::ledger::LedgerEntry;
+
+/// Expired ACME objects are pruned once there are that many.
+const MAX_ACME_ENTRIES: usize = 100_000;
+
+/// Process-local store. Sessions are lost when the process exits, unless a
+/// snapshot file is given to [`LocalStore::open`].
+#[derive(Default)]
+pub struct LocalStore {
+    identities: HashMap<String, Identity>,
+    ledger: Mutex<Vec<LedgerEntry>>,
+    /// File the ledger entries are appended to as they are recorded, so that
+    /// they survive a crash before the next snapshot.
+    journal: Option<File>,
+    revoked: HashSet<String>,
+    acme: HashMap<String, AcmeEntry>,
+    nonces: Mutex<Nonces>,
+    snapshot_path: Option<PathBuf>,
+}
+
+/// Outstanding ACME nonces. They are not kept in the snapshot, as clients get
+/// a new nonce on `badNonce`.
+#[derive(Default)]
+struct Nonces {
+    expires: BTreeMap<String, DateTime<Utc>>,
+    /// The nonces ordered by expiry, oldest first.
+    by_expiry: BTreeSet<(DateTime<Utc>, String)>,
+}
+
+/// Content of a [`LocalStore`] written by [`LocalStore::flush`].
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    identities: Vec<(String, Identity)>,
+    ledger: Vec<LedgerEntry>,
+    revoked: Vec<String>,
+    acme: Vec<(String, AcmeEntry)>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct AcmeEntry {
+    value: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl AcmeEntry {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+}
+
+/// Create the file at `path` only readable by the owner, replacing it if it
+/// exists.
+fn create_private(path: &Path) -> Result<File> {
+    let _ = fs::remove_file(path);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("create {}", path.display()))
+}
+
+/// Append the entries of the journal at `path` following `ledger`, and return
+/// how many were not in it. Unreadable lines are the remains of failed writes
+/// and skipped, as their entries were not recorded.
+fn restore_journal(path: &Path, ledger: &mut Vec<LedgerEntry>) -> Result<usize> {
+    let journal = match fs::read_to_string(path) {
+        std::result::Result::Ok(journal) => journal,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+
+    let mut journaled = 0;
+    for (number, line) in journal.lines().enumerate() {
+        let entry: LedgerEntry = match serde_json::from_str(line) {
+            std::result::Result::Ok(entry) => entry,
+            Err(e) => {
+                warn!("skip line {} of {}: {e}", number + 1, path.display());
+                continue;
+            }
+        };
+        if entry.index < ledger.len() as u64 {
+            continue;
+        }
+        if entry.index > ledger.len() as u64 {
+            bail!(
+                "ledger entry {} is missing from {}",
+                ledger.len(),
+                path.display()
+            );
+        }
+        ledger.push(entry);
+        journaled += 1;
+    }
+
+    Ok(journaled)
+}
+
+impl LocalStore {
+    /// Restore the store from the snapshot at `path` if there is one, and
+    /// write the snapshot there on [`LocalStore::flush`]. Challenges and ACME
+    /// objects that expired meanwhile are dropped. Ledger entries are also
+    /// appended to the journal next to it, `path` with the extension
+    /// `ledger`, as they are recorded.
+    pub fn open(path: &Path) -> Result<Self> {
+        let journal_path = path.with_extension("ledger");
+        if journal_path == path {
+            bail!(
+                "snapshot {} would be its own ledger journal",
+                path.display()
+            );
+        }
+
+        let snapshot = match fs::read(path) {
+            std::result::Result::Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("malformed snapshot {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+
+        let mut ledger = snapshot.ledger;
+        let journaled = restore_journal(&journal_path, &mut ledger)?;
+        // Rewrite the journal, to start it from a snapshot of an older
+        // version and to drop an entry torn by a crash.
+        let journal = ledger
+            .iter()
+            .map(|entry| Ok(serde_json::to_string(entry)? + "\n"))
+            .collect::<Result<String>>()?;
+        let tmp = journal_path.with_extension("ledger.tmp");
+        create_private(&tmp)?
+            .write_all(journal.as_bytes())
+            .with_context(|| format!("write {}", tmp.display()))?;
+        fs::rename(&tmp, &journal_path)
+            .with_context(|| format!("write {}", journal_path.display()))?;
+        let journal = OpenOptions::new()
+            .append(true)
+            .open(&journal_path)
+            .with_context(|| format!("open {}", journal_path.display()))?;
+
+        let store = Self {
+            journal: Some(journal),
+            snapshot_path: Some(path.to_path_buf()),
+            ..Default::default()
+        };
+        let mut expired = 0;
+        for (id, mut identity) in snapshot.identities {
+            if identity.session.is_expired() {
+                identity.session.reset();
+                identity.trace_context.clear();
+                identity.profile = None;
+                expired += 1;
+            }
+            let _ = store.identities.insert(id, identity);
+        }
+        for serial in snapshot.revoked {
+            let _ = store.revoked.insert(serial);
+        }
+        for (key, entry) in snapshot.acme {
+            if !entry.is_expired() {
+                let _ = store.acme.insert(key, entry);
+            }
+        }
+        info!(
+            "restored {} identities ({expired} expired challenges dropped) and {} ledger entries ({journaled} journaled) from {}",
+            store.identities.len(),
+            ledger.len(),
+            path.display()
+        );
+        *store
+            .ledger
+            .lock()
+            .map_err(|_| anyhow!("ledger poisoned"))? = ledger;
+        Ok(store)
+    }
+
+    /// Write the snapshot, if a path was given to [`LocalStore::open`].
+    pub(crate) async fn flush(&self) -> Result<()> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+
+        let mut snapshot = Snapshot {
+            ledger: self
+                .ledger
+                .lock()
+                .map_err(|_| anyhow!("ledger poisoned"))?
+                .clone(),
+            ..Default::default()
+        };
+        self.identities
+            .scan_async(|id, identity| snapshot.identities.push((id.clone(), identity.clone())))
+            .await;
+        self.revoked
+            .scan_async(|serial| snapshot.revoked.push(serial.clone()))
+            .await;
+        self.acme
+            .scan_async(|key, entry| {
+                if !entry.is_expired() {
+                    snapshot.acme.push((key.clone(), entry.clone()));
+                }
+            })
+            .await;
+
+        // Write aside and rename, so that a crash leaves the previous snapshot.
+        // Like the journal, only the owner may read the sessions in it.
+        let tmp = path.with_extension("tmp");
+        let mut file = create_private(&tmp)?;
+        file.write_all(&serde_json::to_vec(&snapshot)?)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("write {}", path.display()))?;
+        info!(
+            "{} identities and {} ledger entries written to {}",
+            snapshot.identities.len(),
+            snapshot.ledger.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    pub(crate) async fn insert(&self, id: &str, identity: &Identity) -> Result<bool> {
+        Ok(self
+            .identities
+            .insert_async(id.to_string(), identity.clone())
+            .await
+            .is_ok())
+    }
+
+    pub(crate) async fn get(&self, id: &str) -> Result<Option<Identity>> {
+        Ok(self
+            .identities
+            .read_async(id, |_, identity| identity.clone())
+            .await)
+    }
+
+    /// The entry stays locked between the comparison and the write.
+    pub(crate) async fn update(&self, id: &str, identity: &mut Identity) -> Result<bool> {
+        let Some(mut entry) = self.identities.get_async(id).await else {
+            bail!("id {id} not registered");
+        };
+        if entry.get().generation != identity.generation {
+            return Ok(false);
+        }
+
+        identity.generation += 1;
+        *entry.get_mut() = identity.clone();
+        Ok(true)
+    }
+
+    pub(crate) async fn session_states(&self) -> Result<BTreeMap<&'static str, i64>> {
+        let mut states = BTreeMap::new();
+        self.identities
+            .scan_async(|_, identity| *states.entry(identity.session.state()).or_default() += 1)
+            .await;
+        Ok(states)
+    }
+
+    pub(crate) async fn revoke(&self, serial: &str) -> Result<()> {
+        let _ = self.revoked.insert_async(serial.to_string()).await;
+        Ok(())
+    }
+
+    pub(crate) async fn is_revoked(&self, serial: &str) -> Result<bool> {
+        Ok(self.revoked.contains_async(serial).await)
+    }
+
+    pub(crate) async fn last_ledger_entry(&self) -> Result<Option<LedgerEntry>> {
+        let ledger = self.ledger.lock().map_err(|_| anyhow!("ledger poisoned"))?;
+        Ok(ledger.last().cloned())
+    }
+
+    pub(crate) async fn append_ledger_entry(&self, entry: &LedgerEntry) -> Result<bool> {
+        let mut ledger = self.ledger.lock().map_err(|_| anyhow!("ledger poisoned"))?;
+        if ledger.len() as u64 != entry.index {
+            return Ok(false);
+        }
+
+        if let Some(mut journal) = self.journal.as_ref() {
+            let line = serde_json::to_string(entry)? + "\n";
+            journal
+                .write_all(line.as_bytes())
+                .and_then(|_| journal.sync_data())
+                .context("append to the ledger journal")?;
+        }
+        ledger.push(entry.clone());
+        Ok(true)
+    }
+
+    pub(crate) async fn ledger_entries(&self, offset: u64, limit: u64) -> Result<Vec<LedgerEntry>> {
+        let ledger = self.ledger.lock().map_err(|_| anyhow!("ledger poisoned"))?;
+        Ok(ledger
+            .iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    pub(crate) async fn put_acme(&self, key: &str, value: &str, ttl: Option<u64>) -> Result<()> {
+        if self.acme.len() >= MAX_ACME_ENTRIES {
+            self.acme.retain_async(|_, entry| !entry.is_expired()).await;
+        }
+
+        let expires = ttl.map(expires_in).transpose()?;
+        self.acme
+            .entry_async(key.to_string())
+            .await
+            .insert_entry(AcmeEntry {
+                value: value.to_string(),
+                expires,
+            });
+        Ok(())
+    }
+
+    pub(crate) async fn get_acme(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .acme
+            .read_async(key, |_, entry| entry.clone())
+            .await
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.value))
+    }
+
+    /// The entry stays locked between the comparison and the write.
+    pub(crate) async fn replace_acme(
+        &self,
+        key: &str,
+        current: &str,
+        value: &str,
+        ttl: u64,
+    ) -> Result<bool> {
+        let Some(mut entry) = self.acme.get_async(key).await else {
+            return Ok(false);
+        };
+        if entry.get().is_expired() || entry.get().value != current {
+            return Ok(false);
+        }
+
+        *entry.get_mut() = AcmeEntry {
+            value: value.to_string(),
+            expires: Some(expires_in(ttl)?),
+        };
+        Ok(true)
+    }
+
+    pub(crate) async fn add_acme_nonce(&self, nonce: &str, ttl: u64, max: usize) -> Result<()> {
+        let expires = expires_in(ttl)?;
+        let mut nonces = self.nonces.lock().map_err(|_| anyhow!("nonces poisoned"))?;
+        let now = Utc::now();
+        while let Some((oldest, nonce)) = nonces.by_expiry.pop_first() {
+            if oldest > now && nonces.expires.len() < max {
+                nonces.by_expiry.insert((oldest, nonce));
+                break;
+            }
+            nonces.expires.remove(&nonce);
+        }
+
+        nonces.expires.insert(nonce.to_string(), expires);
+        nonces.by_expiry.insert((expires, nonce.to_string()));
+        Ok(())
+    }
+
+    pub(crate) async fn take_acme_nonce(&self, nonce: &str) -> Result<bool> {
+        let mut nonces = self.nonces.lock().map_err(|_| anyhow!("nonces poisoned"))?;
+        let Some(expires) = nonces.expires.remove(nonce) else {
+            return Ok(false);
+        };
+        nonces.by_expiry.remove(&(expires, nonce.to_string()));
+        Ok(expires > Utc::now())
+    }
+}
+
+fn expires_in(ttl: u64) -> Result<DateTime<Utc>> {
+    Ok(Utc::now() + Duration::try_seconds(i64::try_from(ttl)?).context("illegal ACME ttl")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use kbs_types::Tee;
+
+    use super::*;
+    use crate::{
+        ledger::{Issuance, Ledger},
+        store::SessionStore,
+    };
+
+    async fn record(store: &SessionStore, serial: &str) {
+        Ledger::default()
+            .record(
+                store,
+                Issuance {
+                    serial,
+                    id: "w",
+                    tee: Tee::Sample,
+                    nonce: "nonce",
+                    policy_ids: &[],
+                    evidence: "evidence",
+                    as_token: "a.b.c",
+                    not_after: Utc::now(),
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn serials(store: &SessionStore) -> Vec<String> {
+        store
+            .ledger_entries(0, 100)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.serial)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn ledger_journal() {
+        let dir = std::env::temp_dir().join(format!("aas-ledger-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+
+        let store = SessionStore::Local(LocalStore::open(&path).unwrap());
+        record(&store, "01").await;
+        store.flush().await.unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        record(&store, "02").await;
+        // Crash before the next snapshot, in the middle of a write.
+        drop(store);
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join("snapshot.ledger"))
+            .unwrap()
+            .write_all(b"{\"index\":2,")
+            .unwrap();
+
+        let store = SessionStore::Local(LocalStore::open(&path).unwrap());
+        assert_eq!(serials(&store).await, ["01", "02"]);
+        record(&store, "03").await;
+        drop(store);
+
+        let store = SessionStore::Local(LocalStore::open(&path).unwrap());
+        assert_eq!(serials(&store).await, ["01", "02", "03"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn acme_nonces() {
+        let store = LocalStore::default();
+        for nonce in ["a", "b", "c"] {
+            store.add_acme_nonce(nonce, 60, 2).await.unwrap();
+        }
+
+        // The oldest one was dropped.
+        assert!(!store.take_acme_nonce("a").await.unwrap());
+        assert!(store.take_acme_nonce("b").await.unwrap());
+        assert!(!store.take_acme_nonce("b").await.unwrap());
+        assert!(store.take_acme_nonce("c").await.unwrap());
+
+        store.add_acme_nonce("d", 0, 2).await.unwrap();
+        assert!(!store.take_acme_nonce("d").await.unwrap());
+        let nonces = store.nonces.lock().unwrap();
+        assert!(nonces.expires.is_empty() && nonces.by_expiry.is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Storage of registered identities and their RCAR session state.
+//!
+//! The [`SessionStore::Local`] backend keeps everything inside the process,
+//! which means both `/rcar/auth` and `/rcar/attest` of one handshake must
+//! reach the same server. The [`SessionStore::Redis`] backend shares the
+//! state between several servers behind a load balancer. Both also keep the
+//! ACME accounts, orders and nonces.
+
+pub mod local;
+pub mod redis;
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ca::profile::CertProfile, ledger::LedgerEntry, server::Metadata, session::SessionStatus,
+};
+
+/// A registered identity together with the state of its RCAR handshake.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Identity {
+    pub metadata: Metadata,
+    pub session: SessionStatus,
+    /// Trace context of the handshake in progress, see [`crate::telemetry`].
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trace_context: HashMap<String, String>,
+    /// Profile requested by the handshake in progress, instead of the
+    /// registered one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<CertProfile>,
+    /// Incremented on every update, see [`SessionStore::update`].
+    #[serde(default)]
+    pub generation: u64,
+}
+
+pub enum SessionStore {
+    Local(local::LocalStore),
+    Redis(redis::RedisStore),
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::Local(local::LocalStore::default())
+    }
+}
+
+impl SessionStore {
+    /// Insert a new identity. Returns `false` if the id is already taken.
+    pub(crate) async fn insert(&self, id: &str, identity: &Identity) -> Result<bool> {
+        match self {
+            SessionStore::Local(inner) => inner.insert(id, identity).await,
+            SessionStore::Redis(inner) => inner.insert(id, identity).await,
+        }
+    }
+
+    pub(crate) async fn get(&self, id: &str) -> Result<Option<Identity>> {
+        match self {
+            SessionStore::Local(inner) => inner.get(id).await,
+            SessionStore::Redis(inner) => inner.get(id).await,
+        }
+    }
+
+    /// Overwrite the state of an already registered identity, unless it has
+    /// been updated since `identity` was read. On success the generation of
+    /// `identity` is incremented; returns `false` if it was outdated.
+    pub(crate) async fn update(&self, id: &str, identity: &mut Identity) -> Result<bool> {
+        match self {
+            SessionStore::Local(inner) => inner.update(id, identity).await,
+            SessionStore::Redis(inner) => inner.update(id, identity).await,
+        }
+    }
+
+    /// Number of handshakes waiting for evidence that would be lost if this
+    /// process exits. Those in [`SessionStore::Redis`] can be completed by
+    /// any server.
+    pub(crate) async fn pending_handshakes(&self) -> Result<i64> {
+        match self {
+            SessionStore::Local(inner) => {
+                let states = inner.session_states().await?;
+                Ok(["authed", "renewing", "attesting"]
+                    .iter()
+                    .filter_map(|state| states.get(state))
+                    .sum())
+            }
+            SessionStore::Redis(_) => Ok(0),
+        }
+    }
+
+    /// Persist what is only kept in memory. Every change is already persisted
+    /// by [`SessionStore::Redis`].
+    pub(crate) async fn flush(&self) -> Result<()> {
+        match self {
+            SessionStore::Local(inner) => inner.flush().await,
+            SessionStore::Redis(_) => Ok(()),
+        }
+    }
+
+    /// Check that the backend is reachable.
+    pub(crate) async fn ping(&self) -> Result<()> {
+        match self {
+            SessionStore::Local(_) => Ok(()),
+            SessionStore::Redis(inner) => inner.ping().await,
+        }
+    }
+
+    /// Number of registered identities by [`SessionStatus::state`].
+    pub(crate) async fn session_states(&self) -> Result<BTreeMap<&'static str, i64>> {
+        match self {
+            SessionStore::Local(inner) => inner.session_states().await,
+            SessionStore::Redis(inner) => inner.session_states().await,
+        }
+    }
+
+    pub(crate) async fn last_ledger_entry(&self) -> Result<Option<LedgerEntry>> {
+        match self {
+            SessionStore::Local(inner) => inner.last_ledger_entry().await,
+            SessionStore::Redis(inner) => inner.last_ledger_entry().await,
+        }
+    }
+
+    /// Append `entry` to the ledger if its index is the next free one.
+    /// Returns `false` if that index is already taken.
+    pub(crate) async fn append_ledger_entry(&self, entry: &LedgerEntry) -> Result<bool> {
+        match self {
+            SessionStore::Local(inner) => inner.append_ledger_entry(entry).await,
+            SessionStore::Redis(inner) => inner.append_ledger_entry(entry).await,
+        }
+    }
+
+    /// Mark the certificate of `serial` as revoked.
+    pub(crate) async fn revoke(&self, serial: &str) -> Result<()> {
+        match self {
+            SessionStore::Local(inner) => inner.revoke(serial).await,
+            SessionStore::Redis(inner) => inner.revoke(serial).await,
+        }
+    }
+
+    pub(crate) async fn is_revoked(&self, serial: &str) -> Result<bool> {
+        match self {
+            SessionStore::Local(inner) => inner.is_revoked(serial).await,
+            SessionStore::Redis(inner) => inner.is_revoked(serial).await,
+        }
+    }
+
+    /// At most `limit` ledger entries starting from index `offset`.
+    pub(crate) async fn ledger_entries(&self, offset: u64, limit: u64) -> Result<Vec<LedgerEntry>> {
+        match self {
+            SessionStore::Local(inner) => inner.ledger_entries(offset, limit).await,
+            SessionStore::Redis(inner) => inner.ledger_entries(offset, limit).await,
+        }
+    }
+
+    /// Store a serialized ACME object, see [`crate::acme`]. It is dropped
+    /// after `ttl` seconds if given.
+    pub(crate) async fn put_acme(&self, key: &str, value: &str, ttl: Option<u64>) -> Result<()> {
+        match self {
+            SessionStore::Local(inner) => inner.put_acme(key, value, ttl).await,
+            SessionStore::Redis(inner) => inner.put_acme(key, value, ttl).await,
+        }
+    }
+
+    pub(crate) async fn get_acme(&self, key: &str) -> Result<Option<String>> {
+        match self {
+            SessionStore::Local(inner) => inner.get_acme(key).await,
+            SessionStore::Redis(inner) => inner.get_acme(key).await,
+        }
+    }
+
+    /// Replace an ACME object by `value`, dropped after `ttl` seconds, only if
+    /// it is still `current`. Returns whether it was replaced.
+    pub(crate) async fn replace_acme(
+        &self,
+        key: &str,
+        current: &str,
+        value: &str,
+        ttl: u64,
+    ) -> Result<bool> {
+        match self {
+            SessionStore::Local(inner) => inner.replace_acme(key, current, value, ttl).await,
+            SessionStore::Redis(inner) => inner.replace_acme(key, current, value, ttl).await,
+        }
+    }
+
+    /// Add an ACME anti-replay nonce valid for `ttl` seconds. The oldest
+    /// nonces are dropped beyond `max` outstanding ones.
+    pub(crate) async fn add_acme_nonce(&self, nonce: &str, ttl: u64, max: usize) -> Result<()> {
+        match self {
+            SessionStore::Local(inner) => inner.add_acme_nonce(nonce, ttl, max).await,
+            SessionStore::Redis(inner) => inner.add_acme_nonce(nonce, ttl, max).await,
+        }
+    }
+
+    /// Use up an ACME nonce, returning whether it was outstanding.
+    pub(crate) async fn take_acme_nonce(&self, nonce: &str) -> Result<bool> {
+        match self {
+            SessionStore::Local(inner) => inner.take_acme_nonce(nonce).await,
+            SessionStore::Redis(inner) => inner.take_acme_nonce(nonce).await,
+        }
+    }
+}
@@ -0,0 +1,577 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+
+use anyhow::*;
+use chrono::Utc;
+use redis::{
+    aio::{ConnectionLike, ConnectionManager},
+    AsyncCommands, Client, Cmd, Pipeline, RedisFuture, Script, SetExpiry, SetOptions, Value,
+};
+use tokio::sync::OnceCell;
+
+use super::Identity;
+use crate::ledger::LedgerEntry;
+
+/// Push `ARGV[2]` to the list `KEYS[1]` only if the list has `ARGV[1]`
+/// elements, so that concurrent servers can not take the same index.
+const APPEND_SCRIPT: &str = r"
+if redis.call('LLEN', KEYS[1]) == tonumber(ARGV[1]) then
+    redis.call('RPUSH', KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// Replace the identity `KEYS[1]` by `ARGV[2]` only if its generation is
+/// still `ARGV[1]`. Returns -1 if there is no such identity.
+const UPDATE_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if not current then
+    return -1
+end
+if (cjson.decode(current).generation or 0) ~= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'KEEPTTL')
+return 1
+";
+
+/// Replace `KEYS[1]` by `ARGV[2]` expiring in `ARGV[3]` seconds, only if it is
+/// still `ARGV[1]`.
+const REPLACE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+return 1
+";
+
+/// Add the nonce `ARGV[1]` expiring at `ARGV[2]` to the sorted set `KEYS[1]`,
+/// after dropping those expired at `ARGV[3]`, and keep the `ARGV[4]` newest.
+const ADD_NONCE_SCRIPT: &str = r"
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', ARGV[3])
+redis.call('ZADD', KEYS[1], ARGV[2], ARGV[1])
+redis.call('ZREMRANGEBYRANK', KEYS[1], 0, -tonumber(ARGV[4]) - 1)
+return 1
+";
+
+/// Remove the nonce `ARGV[1]` from the sorted set `KEYS[1]`, and return 1 if
+/// it was there and expires after `ARGV[2]`.
+const TAKE_NONCE_SCRIPT: &str = r"
+local expires = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not expires then
+    return 0
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+if tonumber(expires) <= tonumber(ARGV[2]) then
+    return 0
+end
+return 1
+";
+
+/// Store backed by a server speaking the Redis protocol, so that several
+/// `aas` instances can serve the same RCAR handshake.
+pub struct RedisStore {
+    client: Client,
+    connection: OnceCell<Connection>,
+    prefix: String,
+}
+
+/// A connection that reconnects after Redis restarted, or a mock in tests.
+#[derive(Clone)]
+enum Connection {
+    Manager(ConnectionManager),
+    #[cfg(test)]
+    Mock(redis_test::MockRedisConnection),
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Connection::Manager(inner) => inner.req_packed_command(cmd),
+            #[cfg(test)]
+            Connection::Mock(inner) => inner.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Connection::Manager(inner) => inner.req_packed_commands(cmd, offset, count),
+            #[cfg(test)]
+            Connection::Mock(inner) => inner.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Manager(inner) => inner.get_db(),
+            #[cfg(test)]
+            Connection::Mock(inner) => inner.get_db(),
+        }
+    }
+}
+
+impl RedisStore {
+    /// `url` follows the `redis://[<username>][:<password>@]<host>[:<port>][/<db>]`
+    /// format. All keys are put under `prefix`.
+    pub fn new(url: &str, prefix: String) -> Result<Self> {
+        let client = Client::open(url).context("invalid redis url")?;
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+            prefix,
+        })
+    }
+
+    async fn connection(&self) -> Result<Connection> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| async {
+                self.client
+                    .get_connection_manager()
+                    .await
+                    .map(Connection::Manager)
+            })
+            .await
+            .context("connect to redis")?;
+        Ok(connection.clone())
+    }
+
+    fn key(&self, id: &str) -> String {
+        format!("{}:identity:{id}", self.prefix)
+    }
+
+    fn ledger_key(&self) -> String {
+        format!("{}:ledger", self.prefix)
+    }
+
+    fn revoked_key(&self) -> String {
+        format!("{}:revoked", self.prefix)
+    }
+
+    fn acme_key(&self, key: &str) -> String {
+        format!("{}:acme:{key}", self.prefix)
+    }
+
+    pub(crate) async fn insert(&self, id: &str, identity: &Identity) -> Result<bool> {
+        let value = serde_json::to_string(identity)?;
+        let options = SetOptions::default().conditional_set(redis::ExistenceCheck::NX);
+        let res: Option<String> = self
+            .connection()
+            .await?
+            .set_options(self.key(id), value, options)
+            .await?;
+        Ok(res.is_some())
+    }
+
+    pub(crate) async fn get(&self, id: &str) -> Result<Option<Identity>> {
+        let value: Option<String> = self.connection().await?.get(self.key(id)).await?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+
+        let identity = serde_json::from_str(&value).context("malformed identity in redis")?;
+        Ok(Some(identity))
+    }
+
+    pub(crate) async fn update(&self, id: &str, identity: &mut Identity) -> Result<bool> {
+        let expected = identity.generation;
+        let mut updated = identity.clone();
+        updated.generation += 1;
+        let value = serde_json::to_string(&updated)?;
+        let res: i64 = Script::new(UPDATE_SCRIPT)
+            .key(self.key(id))
+            .arg(expected)
+            .arg(value)
+            .invoke_async(&mut self.connection().await?)
+            .await?;
+        match res {
+            -1 => bail!("id {id} not registered"),
+            0 => Ok(false),
+            _ => {
+                identity.generation = updated.generation;
+                Ok(true)
+            }
+        }
+    }
+
+    pub(crate) async fn ping(&self) -> Result<()> {
+        let _: String = redis::cmd("PING")
+            .query_async(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    /// Scans every identity, which is linear in their number.
+    pub(crate) async fn session_states(&self) -> Result<BTreeMap<&'static str, i64>> {
+        let mut connection = self.connection().await?;
+        let mut keys: Vec<String> = Vec::new();
+        {
+            let mut iter = connection.scan_match(self.key("*")).await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+
+        let mut states = BTreeMap::new();
+        for chunk in keys.chunks(100) {
+            let values: Vec<Option<String>> = connection.mget(chunk).await?;
+            for value in values.into_iter().flatten() {
+                let identity: Identity =
+                    serde_json::from_str(&value).context("malformed identity in redis")?;
+                *states.entry(identity.session.state()).or_default() += 1;
+            }
+        }
+        Ok(states)
+    }
+
+    pub(crate) async fn revoke(&self, serial: &str) -> Result<()> {
+        let _: i64 = self
+            .connection()
+            .await?
+            .sadd(self.revoked_key(), serial)
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn is_revoked(&self, serial: &str) -> Result<bool> {
+        let revoked = self
+            .connection()
+            .await?
+            .sismember(self.revoked_key(), serial)
+            .await?;
+        Ok(revoked)
+    }
+
+    pub(crate) async fn last_ledger_entry(&self) -> Result<Option<LedgerEntry>> {
+        let value: Option<String> = self
+            .connection()
+            .await?
+            .lindex(self.ledger_key(), -1)
+            .await?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+
+        let entry = serde_json::from_str(&value).context("malformed ledger entry in redis")?;
+        Ok(Some(entry))
+    }
+
+    pub(crate) async fn append_ledger_entry(&self, entry: &LedgerEntry) -> Result<bool> {
+        let value = serde_json::to_string(entry)?;
+        let appended: i64 = Script::new(APPEND_SCRIPT)
+            .key(self.ledger_key())
+            .arg(entry.index)
+            .arg(value)
+            .invoke_async(&mut self.connection().await?)
+            .await?;
+        Ok(appended == 1)
+    }
+
+    pub(crate) async fn ledger_entries(&self, offset: u64, limit: u64) -> Result<Vec<LedgerEntry>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Redis clamps the range to the list, so a range beyond it is fine.
+        let start = isize::try_from(offset).unwrap_or(isize::MAX);
+        let stop = isize::try_from(offset.saturating_add(limit - 1)).unwrap_or(isize::MAX);
+        let values: Vec<String> = self
+            .connection()
+            .await?
+            .lrange(self.ledger_key(), start, stop)
+            .await?;
+        values
+            .iter()
+            .map(|value| serde_json::from_str(value).context("malformed ledger entry in redis"))
+            .collect()
+    }
+
+    pub(crate) async fn put_acme(&self, key: &str, value: &str, ttl: Option<u64>) -> Result<()> {
+        let mut options = SetOptions::default();
+        if let Some(ttl) = ttl {
+            options = options.with_expiration(SetExpiry::EX(ttl as usize));
+        }
+        let _: () = self
+            .connection()
+            .await?
+            .set_options(self.acme_key(key), value, options)
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn get_acme(&self, key: &str) -> Result<Option<String>> {
+        let value = self.connection().await?.get(self.acme_key(key)).await?;
+        Ok(value)
+    }
+
+    pub(crate) async fn replace_acme(
+        &self,
+        key: &str,
+        current: &str,
+        value: &str,
+        ttl: u64,
+    ) -> Result<bool> {
+        let replaced: i64 = Script::new(REPLACE_SCRIPT)
+            .key(self.acme_key(key))
+            .arg(current)
+            .arg(value)
+            .arg(ttl)
+            .invoke_async(&mut self.connection().await?)
+            .await?;
+        Ok(replaced == 1)
+    }
+
+    pub(crate) async fn add_acme_nonce(&self, nonce: &str, ttl: u64, max: usize) -> Result<()> {
+        let now = Utc::now().timestamp();
+        let _: i64 = Script::new(ADD_NONCE_SCRIPT)
+            .key(self.acme_key("nonces"))
+            .arg(nonce)
+            .arg(now.saturating_add_unsigned(ttl))
+            .arg(now)
+            .arg(max)
+            .invoke_async(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn take_acme_nonce(&self, nonce: &str) -> Result<bool> {
+        let taken: i64 = Script::new(TAKE_NONCE_SCRIPT)
+            .key(self.acme_key("nonces"))
+            .arg(nonce)
+            .arg(Utc::now().timestamp())
+            .invoke_async(&mut self.connection().await?)
+            .await?;
+        Ok(taken == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::result::Result::Ok;
+
+    use chrono::Duration;
+    use kbs_types::Tee;
+    use redis::{cmd, ErrorKind, RedisError};
+    use redis_test::{MockCmd, MockRedisConnection};
+
+    use super::*;
+    use crate::{server::Metadata, session::SessionStatus};
+
+    fn store(commands: Vec<MockCmd>) -> RedisStore {
+        RedisStore {
+            client: Client::open("redis://localhost").unwrap(),
+            connection: OnceCell::new_with(Some(Connection::Mock(MockRedisConnection::new(
+                commands,
+            )))),
+            prefix: "aas".to_string(),
+        }
+    }
+
+    fn identity(session: SessionStatus, generation: u64) -> Identity {
+        Identity {
+            metadata: Metadata {
+                policy_ids: vec!["default".to_string()],
+                allowed_resources: Default::default(),
+                issuance: Default::default(),
+                tees: Default::default(),
+            },
+            session,
+            trace_context: Default::default(),
+            profile: None,
+            generation,
+        }
+    }
+
+    fn unregistered(generation: u64) -> Identity {
+        identity(
+            SessionStatus::UnRegistered {
+                id: "w".to_string(),
+            },
+            generation,
+        )
+    }
+
+    fn update_cmd(expected: u64, identity: &Identity) -> Cmd {
+        let mut cmd = cmd("EVALSHA");
+        cmd.arg(Script::new(UPDATE_SCRIPT).get_hash())
+            .arg(1)
+            .arg("aas:identity:w")
+            .arg(expected)
+            .arg(serde_json::to_string(identity).unwrap());
+        cmd
+    }
+
+    #[tokio::test]
+    async fn insert_only_new_ids() {
+        let value = serde_json::to_string(&unregistered(0)).unwrap();
+        let set = || {
+            cmd("SET")
+                .arg("aas:identity:w")
+                .arg(&value)
+                .arg("NX")
+                .clone()
+        };
+        let store = store(vec![
+            MockCmd::new(set(), Ok("OK")),
+            MockCmd::new(set(), Ok(Value::Nil)),
+        ]);
+
+        assert!(store.insert("w", &unregistered(0)).await.unwrap());
+        assert!(!store.insert("w", &unregistered(0)).await.unwrap());
+    }
+
+    fn script_cmd(script: &str, key: &str, args: &[&str]) -> Cmd {
+        let mut cmd = cmd("EVALSHA");
+        cmd.arg(Script::new(script).get_hash()).arg(1).arg(key);
+        for arg in args {
+            cmd.arg(arg);
+        }
+        cmd
+    }
+
+    #[tokio::test]
+    async fn acme_objects() {
+        let store = store(vec![
+            MockCmd::new(
+                cmd("SET")
+                    .arg("aas:acme:order:o")
+                    .arg("{}")
+                    .arg("EX")
+                    .arg(50),
+                Ok("OK"),
+            ),
+            MockCmd::new(cmd("SET").arg("aas:acme:account:a").arg("{}"), Ok("OK")),
+            MockCmd::new(
+                script_cmd(REPLACE_SCRIPT, "aas:acme:order:o", &["{}", "[]", "60"]),
+                Ok(1),
+            ),
+            MockCmd::new(
+                script_cmd(REPLACE_SCRIPT, "aas:acme:order:o", &["{}", "[]", "60"]),
+                Ok(0),
+            ),
+        ]);
+
+        store.put_acme("order:o", "{}", Some(50)).await.unwrap();
+        store.put_acme("account:a", "{}", None).await.unwrap();
+        assert!(store.replace_acme("order:o", "{}", "[]", 60).await.unwrap());
+        assert!(!store.replace_acme("order:o", "{}", "[]", 60).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn ledger_range_saturates() {
+        let store = store(vec![
+            MockCmd::new(
+                cmd("LRANGE").arg("aas:ledger").arg(10).arg(19),
+                Ok(Value::Bulk(vec![])),
+            ),
+            MockCmd::new(
+                cmd("LRANGE")
+                    .arg("aas:ledger")
+                    .arg(10)
+                    .arg(isize::MAX as i64),
+                Ok(Value::Bulk(vec![])),
+            ),
+            MockCmd::new(
+                cmd("LRANGE")
+                    .arg("aas:ledger")
+                    .arg(isize::MAX as i64)
+                    .arg(isize::MAX as i64),
+                Ok(Value::Bulk(vec![])),
+            ),
+        ]);
+
+        assert!(store.ledger_entries(10, 10).await.unwrap().is_empty());
+        assert!(store.ledger_entries(10, u64::MAX).await.unwrap().is_empty());
+        assert!(store
+            .ledger_entries(u64::MAX, u64::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(store.ledger_entries(0, 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_identity() {
+        let value = serde_json::to_string(&unregistered(3)).unwrap();
+        let store = store(vec![
+            MockCmd::new(cmd("GET").arg("aas:identity:w"), Ok(value)),
+            MockCmd::new(cmd("GET").arg("aas:identity:x"), Ok(Value::Nil)),
+            MockCmd::new(cmd("GET").arg("aas:identity:y"), Ok("{")),
+        ]);
+
+        let identity = store.get("w").await.unwrap().unwrap();
+        assert_eq!(identity.generation, 3);
+        assert_eq!(identity.session.state(), "unregistered");
+        assert!(store.get("x").await.unwrap().is_none());
+        assert!(store.get("y").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn update_compares_generation() {
+        let store = store(vec![
+            MockCmd::new(update_cmd(1, &unregistered(2)), Ok(1)),
+            MockCmd::new(update_cmd(2, &unregistered(3)), Ok(0)),
+            MockCmd::new(update_cmd(2, &unregistered(3)), Ok(-1)),
+        ]);
+
+        let mut identity = unregistered(1);
+        assert!(store.update("w", &mut identity).await.unwrap());
+        assert_eq!(identity.generation, 2);
+        assert!(!store.update("w", &mut identity).await.unwrap());
+        assert_eq!(identity.generation, 2);
+        assert!(store.update("w", &mut identity).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn update_loads_missing_script() {
+        let mut load = cmd("SCRIPT");
+        load.arg("LOAD").arg(UPDATE_SCRIPT);
+        let store = store(vec![
+            MockCmd::new(
+                update_cmd(0, &unregistered(1)),
+                Err::<i64, _>(RedisError::from((ErrorKind::NoScriptError, "NOSCRIPT"))),
+            ),
+            MockCmd::new(load, Ok(Script::new(UPDATE_SCRIPT).get_hash())),
+            MockCmd::new(update_cmd(0, &unregistered(1)), Ok(1)),
+        ]);
+
+        assert!(store.update("w", &mut unregistered(0)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_challenge() {
+        let challenged = |timeout| {
+            identity(
+                SessionStatus::Authed {
+                    tee: Tee::Sample,
+                    nonce: "nonce".to_string(),
+                    id: "w".to_string(),
+                    timeout,
+                },
+                1,
+            )
+        };
+        let expired = serde_json::to_string(&challenged(Utc::now() - Duration::seconds(1)));
+        let pending = serde_json::to_string(&challenged(Utc::now() + Duration::seconds(60)));
+        let store = store(vec![
+            MockCmd::new(cmd("GET").arg("aas:identity:w"), Ok(expired.unwrap())),
+            MockCmd::new(cmd("GET").arg("aas:identity:w"), Ok(pending.unwrap())),
+        ]);
+
+        let identity = store.get("w").await.unwrap().unwrap();
+        assert!(identity.session.is_expired());
+        assert_eq!(identity.session.state(), "expired");
+        let identity = store.get("w").await.unwrap().unwrap();
+        assert!(!identity.session.is_expired());
+        assert_eq!(identity.session.state(), "authed");
+    }
+}
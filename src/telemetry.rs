@@ -0,0 +1,36 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trace context of RCAR handshakes, propagated with the global
+//! OpenTelemetry propagator, e.g. W3C `traceparent` headers.
+//!
+//! The context of the span serving `/rcar/auth` (or a renewal) is kept with
+//! the session, so that serving `/rcar/attest` and calling the attestation
+//! service join the same trace. Nothing is propagated unless the
+//! application installs a propagator and an OpenTelemetry tracing layer.
+
+use std::collections::HashMap;
+
+use opentelemetry::global;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Propagation fields, e.g. `traceparent`, of the current span.
+pub fn current_context() -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let cx = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut fields));
+    fields
+}
+
+/// Continue the trace of the propagation `fields` in `span`. Must be called
+/// before `span` is entered.
+pub fn set_parent(span: &Span, fields: &HashMap<String, String>) {
+    if fields.is_empty() {
+        return;
+    }
+
+    let cx = global::get_text_map_propagator(|propagator| propagator.extract(fields));
+    let _ = span.set_parent(cx);
+}
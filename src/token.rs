@@ -0,0 +1,372 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Short-lived ES256 JWT access tokens, issued after a successful RCAR
+//! handshake for clients that cannot use the issued certificate for mTLS.
+
+use anyhow::*;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use kbs_types::Tee;
+use p256::{
+    ecdsa::{signature::Signer, signature::Verifier, Signature, SigningKey, VerifyingKey},
+    pkcs8::DecodePrivateKey,
+    PublicKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::ca::rotation::KeyRing;
+
+/// Tolerated clock skew between the issuer and the verifier.
+const LEEWAY_SECS: i64 = 60;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    /// The registered identity the token is issued to.
+    pub sub: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub tee: Tee,
+    pub allowed_resources: Vec<String>,
+}
+
+/// A P-256 key signing access tokens, identified by its `kid`.
+#[derive(Clone)]
+pub struct TokenKey {
+    key: SigningKey,
+    kid: String,
+}
+
+impl TokenKey {
+    /// `private_key` is a PKCS#8 PEM encoded P-256 private key.
+    pub fn new(private_key: &str) -> Result<Self> {
+        let key = SigningKey::from_pkcs8_pem(private_key)
+            .map_err(|e| anyhow!("token signing key must be a P-256 PKCS#8 key: {e}"))?;
+        let kid = key_id(key.verifying_key());
+        Ok(Self { key, kid })
+    }
+
+    /// Key id put in the `kid` header of the tokens signed by this key.
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn verifying_key(&self) -> &VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    /// The verifying key as a JSON Web Key (RFC 7517).
+    pub fn jwk(&self) -> Result<Value> {
+        let mut jwk = serde_json::to_value(PublicKey::from(self.verifying_key()).to_jwk())?;
+        let Value::Object(fields) = &mut jwk else {
+            bail!("JWK is not a JSON object");
+        };
+
+        fields.insert("kid".into(), self.kid.clone().into());
+        fields.insert("use".into(), "sig".into());
+        fields.insert("alg".into(), "ES256".into());
+        Ok(jwk)
+    }
+}
+
+/// Issues and verifies the access tokens, with a dedicated key or else with
+/// the CA keys of a [`KeyRing`]. Then tokens are signed by the active CA key
+/// and accepted from the verify-only ones, so that they follow its rotation.
+pub struct TokenIssuer {
+    key: Option<TokenKey>,
+    issuer: String,
+    audience: String,
+    ttl: i64,
+}
+
+impl TokenIssuer {
+    /// `private_key` is a PKCS#8 PEM encoded P-256 private key, or `None` to
+    /// sign with the CA keys. Tokens are valid for `ttl` seconds.
+    pub fn new(
+        private_key: Option<&str>,
+        issuer: String,
+        audience: String,
+        ttl: i64,
+    ) -> Result<Self> {
+        Ok(Self {
+            key: private_key.map(TokenKey::new).transpose()?,
+            issuer,
+            audience,
+            ttl,
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// Whether the tokens are signed by the CA keys of the [`KeyRing`].
+    pub fn uses_ca_keys(&self) -> bool {
+        self.key.is_none()
+    }
+
+    /// The key signing new tokens.
+    pub fn signing_key<'a>(&'a self, ring: &'a KeyRing) -> Result<&'a TokenKey> {
+        match &self.key {
+            Some(key) => Ok(key),
+            None => ring.active_token_key(),
+        }
+    }
+
+    /// The keys whose tokens are accepted, which are also published.
+    pub fn verifying_keys<'a>(&'a self, ring: &'a KeyRing) -> Vec<&'a TokenKey> {
+        match &self.key {
+            Some(key) => vec![key],
+            None => ring.token_keys().collect(),
+        }
+    }
+
+    /// JSON Web Keys of [`TokenIssuer::verifying_keys`].
+    pub fn jwks(&self, ring: &KeyRing) -> Result<Vec<Value>> {
+        self.verifying_keys(ring)
+            .into_iter()
+            .map(TokenKey::jwk)
+            .collect()
+    }
+
+    pub fn issue(
+        &self,
+        ring: &KeyRing,
+        id: &str,
+        tee: Tee,
+        mut allowed_resources: Vec<String>,
+    ) -> Result<String> {
+        let key = self.signing_key(ring)?;
+        allowed_resources.sort();
+        let now = Utc::now();
+        let claims = Claims {
+            iss: self.issuer.clone(),
+            sub: id.to_string(),
+            aud: self.audience.clone(),
+            iat: now.timestamp(),
+            exp: (now + Duration::try_seconds(self.ttl).context("illegal token ttl")?).timestamp(),
+            tee,
+            allowed_resources,
+        };
+        let header = Header {
+            alg: "ES256".into(),
+            typ: "JWT".into(),
+            kid: key.kid.clone(),
+        };
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature: Signature = key.key.sign(signing_input.as_bytes());
+        Ok(format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+
+    /// Verify the signature, issuer, audience and validity period of `token`.
+    pub fn verify(&self, ring: &KeyRing, token: &str) -> Result<Claims> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            bail!("malformed token");
+        };
+
+        let signing_input = format!("{header}.{payload}");
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)
+            .context("malformed token header")?;
+        if header.alg != "ES256" {
+            bail!("unsupported token algorithm {}", header.alg);
+        }
+        let Some(key) = self
+            .verifying_keys(ring)
+            .into_iter()
+            .find(|key| key.kid == header.kid)
+        else {
+            bail!("token signed by unknown key {}", header.kid);
+        };
+
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature)?)
+            .context("malformed token signature")?;
+        key.verifying_key()
+            .verify(signing_input.as_bytes(), &signature)
+            .context("invalid token signature")?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)
+            .context("malformed token claims")?;
+        if claims.iss != self.issuer {
+            bail!("unexpected token issuer {}", claims.iss);
+        }
+        if claims.aud != self.audience {
+            bail!("unexpected token audience {}", claims.aud);
+        }
+
+        let now = Utc::now().timestamp();
+        if claims.exp + LEEWAY_SECS < now {
+            bail!("token expired");
+        }
+        if claims.iat - LEEWAY_SECS > now {
+            bail!("token issued in the future");
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Derive a stable key id from the SHA-256 digest of the SEC1 public key.
+fn key_id(key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(key.to_encoded_point(false).as_bytes());
+    URL_SAFE_NO_PAD.encode(&digest[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ca::{rotation::KeyState, SampleCA, CA};
+
+    fn ca() -> CA {
+        CA::Sample(SampleCA::new(None).unwrap())
+    }
+
+    fn issuer(audience: &str, ttl: i64) -> TokenIssuer {
+        TokenIssuer::new(None, "aas".to_string(), audience.to_string(), ttl).unwrap()
+    }
+
+    fn issue(issuer: &TokenIssuer, ring: &KeyRing) -> String {
+        issuer
+            .issue(
+                ring,
+                "spiffe://w",
+                Tee::Sample,
+                vec!["b".into(), "a".into()],
+            )
+            .unwrap()
+    }
+
+    /// Replace the header of `token`, keeping its claims and signature.
+    fn with_header(token: &str, header: Value) -> String {
+        let (_, rest) = token.split_once('.').unwrap();
+        format!(
+            "{}.{rest}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap())
+        )
+    }
+
+    #[test]
+    fn valid_token() {
+        let ring = KeyRing::new("k1".to_string(), ca());
+        let issuer = issuer("relying-party", 300);
+        let claims = issuer.verify(&ring, &issue(&issuer, &ring)).unwrap();
+        assert_eq!(claims.sub, "spiffe://w");
+        assert_eq!(claims.aud, "relying-party");
+        assert_eq!(claims.allowed_resources, ["a", "b"]);
+    }
+
+    #[test]
+    fn expired_token() {
+        let ring = KeyRing::new("k1".to_string(), ca());
+        let token = issue(&issuer("relying-party", -LEEWAY_SECS - 60), &ring);
+        let e = issuer("relying-party", 300)
+            .verify(&ring, &token)
+            .unwrap_err();
+        assert!(e.to_string().contains("expired"));
+    }
+
+    #[test]
+    fn wrong_audience_or_issuer() {
+        let ring = KeyRing::new("k1".to_string(), ca());
+        let token = issue(&issuer("relying-party", 300), &ring);
+        assert!(issuer("other", 300).verify(&ring, &token).is_err());
+
+        let other_issuer =
+            TokenIssuer::new(None, "other".to_string(), "relying-party".to_string(), 300).unwrap();
+        assert!(other_issuer.verify(&ring, &token).is_err());
+    }
+
+    #[test]
+    fn unknown_kid() {
+        let ring = KeyRing::new("k1".to_string(), ca());
+        let other = KeyRing::new("k1".to_string(), ca());
+        let issuer = issuer("relying-party", 300);
+        let e = issuer.verify(&other, &issue(&issuer, &ring)).unwrap_err();
+        assert!(e.to_string().contains("unknown key"));
+    }
+
+    #[test]
+    fn rotated_and_retired_key() {
+        let mut ring = KeyRing::new("k1".to_string(), ca());
+        let issuer = issuer("relying-party", 300);
+        let token = issue(&issuer, &ring);
+
+        ring.add("k2".to_string(), KeyState::VerifyOnly, ca())
+            .unwrap();
+        ring.promote("k2").unwrap();
+        // Still accepted from the verify-only key.
+        issuer.verify(&ring, &token).unwrap();
+        issuer.verify(&ring, &issue(&issuer, &ring)).unwrap();
+
+        ring.retire("k1").unwrap();
+        assert!(issuer.verify(&ring, &token).is_err());
+    }
+
+    #[test]
+    fn algorithm_confusion() {
+        let ring = KeyRing::new("k1".to_string(), ca());
+        let issuer = issuer("relying-party", 300);
+        let token = issue(&issuer, &ring);
+        let kid = issuer.signing_key(&ring).unwrap().kid().to_string();
+
+        for alg in ["none", "HS256", "RS256", "ES384"] {
+            let forged = with_header(
+                &token,
+                serde_json::json!({ "alg": alg, "typ": "JWT", "kid": kid }),
+            );
+            let e = issuer.verify(&ring, &forged).unwrap_err();
+            assert!(e.to_string().contains("unsupported token algorithm"));
+        }
+
+        // An unsigned token with the right header.
+        let (unsigned, _) = token.rsplit_once('.').unwrap();
+        assert!(issuer.verify(&ring, &format!("{unsigned}.")).is_err());
+        assert!(issuer.verify(&ring, unsigned).is_err());
+    }
+
+    #[test]
+    fn tampered_claims() {
+        let ring = KeyRing::new("k1".to_string(), ca());
+        let issuer = issuer("relying-party", 300);
+        let token = issue(&issuer, &ring);
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        claims["sub"] = "spiffe://other".into();
+        let forged = format!(
+            "{}.{}.{}",
+            parts[0],
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap()),
+            parts[2]
+        );
+        let e = issuer.verify(&ring, &forged).unwrap_err();
+        assert!(e.to_string().contains("invalid token signature"));
+    }
+}